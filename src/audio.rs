@@ -1,11 +1,14 @@
-use std::collections::VecDeque;
+use std::fmt;
 use std::fs::File;
 use std::io::BufReader;
-use std::iter;
-use std::iter::Peekable;
+use std::path::Path;
 use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
 use std::sync::mpsc;
 use std::sync::mpsc::TryRecvError;
+use std::sync::Arc;
+use std::thread;
 use std::time::Instant;
 
 use cpal::traits::DeviceTrait;
@@ -15,23 +18,23 @@ use cpal::OutputCallbackInfo;
 use cpal::Sample;
 use cpal::Stream;
 use cpal::StreamConfig;
-use dasp::signal;
-use dasp::signal::ConstHz;
-use dasp::signal::Delay;
-use dasp::signal::FromIterator;
-use dasp::signal::ScaleAmp;
-use dasp::signal::Sine;
-use dasp::signal::Take;
-use dasp::Signal;
+use crossbeam_channel::Receiver;
+use crossbeam_channel::Sender;
 use derive_getters::Getters;
 use rodio::Decoder;
+use rodio::Source;
 use tokio::sync::watch;
 use universal_audio_decoder::new_uniform_source_iterator;
 use universal_audio_decoder::TrueUniformSourceIterator;
 
-use crate::dasp_signal_ext::Multiplexed;
-use crate::dasp_signal_ext::SignalExt;
 use crate::error::AudioError;
+use crate::metronome::SESchedulesBox;
+use crate::metronome::SoundEffectMixer;
+use crate::metronome::SoundEffectSchedule;
+use crate::schema::MusicInfo;
+use crate::time_stretch::TimeStretch;
+use crate::time_stretch::MAX_SPEED;
+use crate::time_stretch::MIN_SPEED;
 
 #[derive(Getters)]
 pub struct AudioManager {
@@ -39,18 +42,37 @@ pub struct AudioManager {
     _stream: Stream,
     command_sender: mpsc::Sender<AudioCommand>,
     state_receiver: watch::Receiver<AudioState>,
+    #[getter(skip)]
+    event_sender: mpsc::Sender<AudioEvent>,
+
+    #[getter(skip)]
+    music_requests: Sender<MusicRequest>,
+    /// Incremented by every load, so that a load finishing after a later one is discarded
+    #[getter(skip)]
+    music_load_generation: Arc<AtomicUsize>,
 }
 
 pub enum AudioCommand {
     Play,
     Pause,
     Seek(f64),
-    LoadMusic(PathBuf),
 
     SetVolume(f64),
+    /// Song seconds per real second, between `MIN_SPEED` and `MAX_SPEED`. Keeps the pitch.
+    SetPlaybackSpeed(f64),
 
     SetSoundEffectSchedules(SESchedulesBox),
     SetSoundEffectVolume(f64),
+
+    SetLoop(Option<LoopRegion>),
+}
+
+/// While set, playback jumps back to `start` whenever it reaches `end`.
+pub struct LoopRegion {
+    pub start: f64,
+    pub end: f64,
+    /// The sound effects from `start` to `end`, played again on every jump
+    pub schedules: Arc<[SoundEffectSchedule]>,
 }
 
 pub enum AudioState {
@@ -58,18 +80,90 @@ pub enum AudioState {
     Playing {
         instant: Instant,
         music_position: f64,
+        speed: f64,
+    },
+}
+
+/// What happens to the audio, to be shown to the user.
+#[derive(Clone, Debug)]
+pub enum AudioEvent {
+    MusicLoaded {
+        path: PathBuf,
+        duration: Option<f64>,
+        sample_rate: u32,
+        channels: u16,
+    },
+    MusicLoadFailed {
+        path: PathBuf,
+        reason: String,
     },
+    StreamError(String),
+    /// The output device has gone away. No more sound will be played.
+    DeviceLost,
+    /// The audio thread has stopped, e.g. by a panic. No more sound will be played.
+    Stopped,
+}
+
+impl fmt::Display for AudioEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use AudioEvent::*;
+        match self {
+            MusicLoaded {
+                path,
+                duration,
+                sample_rate,
+                channels,
+            } => {
+                let name = path.file_name().unwrap_or_else(|| path.as_os_str());
+                write!(f, "Loaded {} (", name.to_string_lossy())?;
+                if let Some(duration) = duration {
+                    write!(f, "{:.1} s, ", duration)?;
+                }
+                write!(f, "{} Hz, {} ch)", sample_rate, channels)
+            }
+            MusicLoadFailed { path, reason } => {
+                write!(f, "Failed to load {}: {}", path.display(), reason)
+            }
+            StreamError(e) => write!(f, "Audio error: {}", e),
+            DeviceLost => write!(f, "Audio device lost, audio is disabled"),
+            Stopped => write!(f, "Audio stopped, audio is disabled"),
+        }
+    }
 }
 
 impl AudioManager {
-    pub fn new() -> Result<Self, AudioError> {
+    /// Events are sent to `event_sender` from other threads.
+    pub fn new(event_sender: mpsc::Sender<AudioEvent>) -> Result<Self, AudioError> {
         let (command_sender, command_receiver) = mpsc::channel();
         let (state_sender, state_receiver) = watch::channel(AudioState::NotPlaying);
-        let _stream = Self::build_stream(command_receiver, state_sender)?;
+        // The audio callback only polls them, without blocking
+        let (music_requests, request_receiver) = crossbeam_channel::bounded(MUSIC_REQUEST_CAPACITY);
+        let (music_sender, music_receiver) = crossbeam_channel::bounded(MUSIC_REQUEST_CAPACITY);
+        let (_stream, stream_config) = Self::build_stream(
+            command_receiver,
+            state_sender,
+            music_requests.clone(),
+            music_receiver,
+            event_sender.clone(),
+        )?;
+        let music_load_generation = Arc::new(AtomicUsize::new(0));
+        let worker = MusicWorker {
+            stream_config,
+            requests: request_receiver,
+            music_sender,
+            event_sender: event_sender.clone(),
+            load_generation: music_load_generation.clone(),
+            loaded: None,
+        };
+        thread::spawn(move || worker.run());
         let manager = AudioManager {
             _stream,
             command_sender,
             state_receiver,
+            event_sender,
+
+            music_requests,
+            music_load_generation,
         };
         Ok(manager)
     }
@@ -77,7 +171,10 @@ impl AudioManager {
     fn build_stream(
         command_receiver: mpsc::Receiver<AudioCommand>,
         state_sender: watch::Sender<AudioState>,
-    ) -> Result<Stream, AudioError> {
+        music_requests: Sender<MusicRequest>,
+        music_receiver: Receiver<PositionedMusic>,
+        event_sender: mpsc::Sender<AudioEvent>,
+    ) -> Result<(Stream, StreamConfig), AudioError> {
         let host = cpal::default_host();
         let device = host
             .default_output_device()
@@ -92,9 +189,20 @@ impl AudioManager {
         let sample_format = supported_config.sample_format();
         let stream_config = StreamConfig::from(supported_config);
 
-        let callback =
-            AudioOutputCallback::new(stream_config.clone(), command_receiver, state_sender);
-        let error_callback = |err| eprintln!("an error occurred on audio stream: {:?}", err);
+        let callback = AudioOutputCallback::new(
+            stream_config.clone(),
+            command_receiver,
+            state_sender,
+            music_requests,
+            music_receiver,
+        );
+        let error_callback = move |err: cpal::StreamError| {
+            let event = match err {
+                cpal::StreamError::DeviceNotAvailable => AudioEvent::DeviceLost,
+                err => AudioEvent::StreamError(err.to_string()),
+            };
+            let _ = event_sender.send(event);
+        };
         let stream = {
             use cpal::SampleFormat::*;
             let (sc, ec) = (&stream_config, error_callback);
@@ -105,7 +213,29 @@ impl AudioManager {
             }
         }?;
         stream.play()?;
-        Ok(stream)
+        Ok((stream, stream_config))
+    }
+
+    /// Sends the command to the audio thread, or reports `AudioEvent::Stopped` if it has stopped.
+    pub fn send(&self, command: AudioCommand) {
+        if self.command_sender.send(command).is_err() {
+            let _ = self.event_sender.send(AudioEvent::Stopped);
+        }
+    }
+
+    /// Opens and decodes the music on the music worker, which hands it to the audio thread when
+    /// ready. The result is reported as an event.
+    pub fn load_music(&self, path: PathBuf) {
+        let generation = self.music_load_generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let request = MusicRequest::Load { path, generation };
+        if let Err(e) = self.music_requests.send(request) {
+            if let MusicRequest::Load { path, .. } = e.into_inner() {
+                let reason = "the music worker has stopped".to_string();
+                let _ = self
+                    .event_sender
+                    .send(AudioEvent::MusicLoadFailed { path, reason });
+            }
+        }
     }
 }
 
@@ -117,66 +247,278 @@ impl AudioManager {
             Playing {
                 instant,
                 music_position,
+                speed,
             } => {
-                let play_speed = 1.0;
                 let now = Instant::now();
                 let diff = if now > instant {
                     (now - instant).as_secs_f64()
                 } else {
                     -(instant - now).as_secs_f64()
                 };
-                Some(music_position + diff * play_speed)
+                Some(music_position + diff * speed)
+            }
+        }
+    }
+}
+
+/// Reads the metadata of a music file from its header. The hash is left empty, and so is the
+/// duration if the format does not tell it, for `measure_music` to fill in.
+pub fn probe_music(path: &Path) -> anyhow::Result<MusicInfo> {
+    let decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+    Ok(MusicInfo {
+        path: path.canonicalize()?,
+        duration: decoder.total_duration().map_or(0.0, |d| d.as_secs_f64()),
+        sample_rate: decoder.sample_rate(),
+        channels: decoder.channels(),
+        hash: String::new(),
+    })
+}
+
+/// Hashes a music file, and decodes it to the end if the format does not tell the duration. This
+/// reads the whole file, so it should not be done on the UI thread.
+pub fn measure_music(music: &mut MusicInfo) -> anyhow::Result<()> {
+    let decoder = Decoder::new(BufReader::new(File::open(&music.path)?))?;
+    if decoder.total_duration().is_none() {
+        let (sample_rate, channels) = (decoder.sample_rate(), decoder.channels());
+        music.duration = decoder.count() as f64 / channels as f64 / sample_rate as f64;
+    }
+    music.hash = MusicInfo::hash_file(&music.path)?;
+    Ok(())
+}
+
+type MusicDecoder = TrueUniformSourceIterator<Decoder<BufReader<File>>>;
+type MusicSource = TimeStretch<MusicSlot>;
+
+/// Requests from the audio thread are dropped instead of waiting while this many are pending.
+const MUSIC_REQUEST_CAPACITY: usize = 16;
+/// Real seconds a seek is expected to take while playing, doubled whenever one takes longer
+const SEEK_LEAD: f64 = 0.1;
+/// Real seconds the music plays on past the end of a loop while its start is being prepared
+const LOOP_WAIT: f64 = 1.0;
+
+/// The decoder being played, which is away at the music worker while it is seeked.
+struct MusicSlot(Option<MusicDecoder>);
+
+impl Iterator for MusicSlot {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        self.0.as_mut()?.next()
+    }
+}
+
+/// Work that must not be done on the audio thread: file I/O, and freeing decoders.
+enum MusicRequest {
+    Load {
+        path: PathBuf,
+        generation: usize,
+    },
+    /// Moves the decoder to the song time `time` and sends it back to the audio thread.
+    Seek {
+        decoder: MusicDecoder,
+        time: f64,
+        generation: usize,
+        for_loop: bool,
+    },
+    /// Opens the music of the load `generation` again, as the decoder to jump to at the end of
+    /// the loop, and seeks it to `time`.
+    OpenLoop {
+        time: f64,
+        generation: usize,
+    },
+    Drop(MusicDecoder),
+}
+
+/// A decoder whose next sample is at the song time `time`.
+struct PositionedMusic {
+    decoder: MusicDecoder,
+    time: f64,
+    generation: usize,
+    /// Waits at the start of the loop instead of being played right away
+    for_loop: bool,
+}
+
+struct MusicWorker {
+    stream_config: StreamConfig,
+    requests: Receiver<MusicRequest>,
+    music_sender: Sender<PositionedMusic>,
+    event_sender: mpsc::Sender<AudioEvent>,
+    load_generation: Arc<AtomicUsize>,
+    /// The last music loaded, and its generation
+    loaded: Option<(PathBuf, usize)>,
+}
+
+impl MusicWorker {
+    /// Serves the requests until the audio stream is dropped.
+    fn run(mut self) {
+        while let Ok(request) = self.requests.recv() {
+            let music = match request {
+                MusicRequest::Load { path, generation } => {
+                    let music = open_music(&path, &self.stream_config);
+                    if self.load_generation.load(Ordering::SeqCst) != generation {
+                        continue;
+                    }
+                    match music {
+                        Ok((decoder, event)) => {
+                            self.loaded = Some((path, generation));
+                            let _ = self.event_sender.send(event);
+                            PositionedMusic {
+                                decoder,
+                                time: 0.0,
+                                generation,
+                                for_loop: false,
+                            }
+                        }
+                        Err(e) => {
+                            let reason = e.to_string();
+                            let event = AudioEvent::MusicLoadFailed { path, reason };
+                            let _ = self.event_sender.send(event);
+                            continue;
+                        }
+                    }
+                }
+                MusicRequest::Seek {
+                    mut decoder,
+                    time,
+                    generation,
+                    for_loop,
+                } => {
+                    if !self.seek(&mut decoder, time) {
+                        continue;
+                    }
+                    PositionedMusic {
+                        decoder,
+                        time,
+                        generation,
+                        for_loop,
+                    }
+                }
+                MusicRequest::OpenLoop { time, generation } => {
+                    let path = match &self.loaded {
+                        Some((path, loaded)) if *loaded == generation => path,
+                        // Superseded by a later load
+                        _ => continue,
+                    };
+                    let mut decoder = match open_music(path, &self.stream_config) {
+                        Ok((decoder, _)) => decoder,
+                        Err(e) => {
+                            let message = format!("Failed to open the music for the loop: {}", e);
+                            let _ = self.event_sender.send(AudioEvent::StreamError(message));
+                            continue;
+                        }
+                    };
+                    if !self.seek(&mut decoder, time) {
+                        continue;
+                    }
+                    PositionedMusic {
+                        decoder,
+                        time,
+                        generation,
+                        for_loop: true,
+                    }
+                }
+                MusicRequest::Drop(decoder) => {
+                    drop(decoder);
+                    continue;
+                }
+            };
+            if self.music_sender.send(music).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Reports a failure as an `AudioEvent::StreamError`, after which the decoder is dropped and
+    /// the music stays silent until it is loaded again.
+    fn seek(&self, decoder: &mut MusicDecoder, time: f64) -> bool {
+        match decoder.seek(time) {
+            Ok(_) => true,
+            Err(e) => {
+                let message = format!("Failed to seek the music to {:.1} s: {:?}", time, e);
+                let _ = self.event_sender.send(AudioEvent::StreamError(message));
+                false
             }
         }
     }
 }
 
-type MusicSource = TrueUniformSourceIterator<Decoder<BufReader<File>>>;
-type SoundEffect = Multiplexed<Delay<FromIterator<Take<ScaleAmp<Sine<ConstHz>>>>>>;
+/// The music converted to the output format, and the `AudioEvent::MusicLoaded` with the format
+/// of the file.
+fn open_music(
+    path: &Path,
+    stream_config: &StreamConfig,
+) -> anyhow::Result<(MusicDecoder, AudioEvent)> {
+    let file = File::open(path)?;
+    let decoder = Decoder::new(BufReader::new(file))?;
+    let event = AudioEvent::MusicLoaded {
+        path: path.to_owned(),
+        duration: decoder.total_duration().map(|d| d.as_secs_f64()),
+        sample_rate: decoder.sample_rate(),
+        channels: decoder.channels(),
+    };
+    Ok((new_uniform_source_iterator(decoder, stream_config), event))
+}
 
 struct AudioOutputCallback {
     output_stream_config: StreamConfig,
     command_receiver: mpsc::Receiver<AudioCommand>,
     state_sender: watch::Sender<AudioState>,
-
-    music: Option<MusicSource>,
+    music_requests: Sender<MusicRequest>,
+    music_receiver: Receiver<PositionedMusic>,
+
+    /// Plays silence while no decoder is in its slot
+    music: MusicSource,
+    /// Of the load the decoders come from
+    music_generation: usize,
+    /// Set when a seek could not be requested, to retry on the next callback
+    music_needs_seek: bool,
+    /// A second decoder at the start of the loop, swapped in on every jump
+    loop_music: Option<PositionedMusic>,
+    /// Whether a decoder for `loop_music` is being opened or seeked
+    loop_music_pending: bool,
+    seek_lead: f64,
     playing: bool,
     music_volume: f64,
+    speed: f64,
 
+    /// Song time of the next sample
     playback_time: f64,
+    loop_region: Option<LoopRegion>,
 
-    sound_effect_schedules: Peekable<SESchedulesBox>,
-    sound_effects: VecDeque<SoundEffect>,
-    sound_effect_volume: f64,
+    sound_effects: SoundEffectMixer,
 }
 
-#[derive(Debug)]
-pub struct SoundEffectSchedule {
-    pub time: f64,
-    pub frequency: f64,
-}
-pub type SESchedulesBox = Box<dyn Iterator<Item = SoundEffectSchedule> + Send>;
-
 impl AudioOutputCallback {
     fn new(
         output_stream_config: StreamConfig,
         command_receiver: mpsc::Receiver<AudioCommand>,
         state_sender: watch::Sender<AudioState>,
+        music_requests: Sender<MusicRequest>,
+        music_receiver: Receiver<PositionedMusic>,
     ) -> Self {
+        let sample_rate = output_stream_config.sample_rate.0;
+        let channels = output_stream_config.channels;
         Self {
             output_stream_config,
             command_receiver,
             state_sender,
-
-            music: None,
+            music_requests,
+            music_receiver,
+
+            music: TimeStretch::new(MusicSlot(None), channels, sample_rate),
+            music_generation: 0,
+            music_needs_seek: false,
+            loop_music: None,
+            loop_music_pending: false,
+            seek_lead: SEEK_LEAD,
             playing: false,
             music_volume: 0.0,
+            speed: 1.0,
 
             playback_time: 0.0,
+            loop_region: None,
 
-            sound_effect_schedules: Self::empty_schedules(),
-            sound_effects: VecDeque::new(),
-            sound_effect_volume: 0.0,
+            sound_effects: SoundEffectMixer::new(sample_rate, channels),
         }
     }
 }
@@ -193,46 +535,68 @@ impl AudioOutputCallback {
         } {
             self.process_command(command);
         }
+        while let Ok(music) = self.music_receiver.try_recv() {
+            self.receive_music(music);
+        }
+        if self.music_needs_seek {
+            self.request_seek();
+        }
 
         self.refresh_state(callback_info);
+
+        // Split the output where the loop jumps back
+        let channels = self.output_stream_config.channels as usize;
+        let sample_rate = self.output_stream_config.sample_rate.0 as f64;
+        let mut out = out;
+        while !out.is_empty() {
+            let mut frames = out.len() / channels;
+            if let Some(end) = self.loop_region.as_ref().map(|r| r.end) {
+                if self.playing {
+                    if self.playback_time >= end && self.loop_music_ready(end) {
+                        self.jump_to_loop_start();
+                    }
+                    // Past the end, the rest is played while waiting for the loop music
+                    if self.playback_time < end {
+                        let until_end = (end - self.playback_time) / self.speed * sample_rate;
+                        frames = frames.min(until_end.ceil().max(1.0) as usize);
+                    }
+                }
+            }
+            let (chunk, rest) = std::mem::take(&mut out).split_at_mut(frames * channels);
+            self.render(chunk);
+            out = rest;
+        }
+    }
+
+    fn render<S>(&mut self, out: &mut [S])
+    where
+        S: Sample,
+    {
         let playback_end = self.playback_time
             + if self.playing {
-                1.0 / self.output_stream_config.sample_rate.0 as f64 * out.len() as f64
+                self.speed / self.output_stream_config.sample_rate.0 as f64 * out.len() as f64
                     / self.output_stream_config.channels as f64
             } else {
                 0.0
             };
 
-        while let Some(next) = self.sound_effect_schedules.peek() {
-            if playback_end < next.time {
-                break;
-            }
-            let next = self.sound_effect_schedules.next().expect("Always exists");
-            let wave = signal::from_iter(
-                signal::rate(self.output_stream_config.sample_rate.0 as _)
-                    .const_hz(next.frequency)
-                    .sine()
-                    .scale_amp(self.sound_effect_volume)
-                    .take(self.output_stream_config.sample_rate.0 as usize / 20), // 0.05 seconds
-            )
-            .delay(
-                ((next.time - self.playback_time).max(0.0)
-                    * self.output_stream_config.sample_rate.0 as f64) as _,
-            )
-            .multiplexed(self.output_stream_config.channels as _);
-            self.sound_effects.push_back(wave);
-        }
-
-        self.sound_effects.retain(|x| !x.is_exhausted());
+        // The sound effects at the end of the loop are played from its start instead
+        let schedule_end = match &self.loop_region {
+            Some(region) if self.playing => playback_end.min(region.end),
+            _ => playback_end,
+        };
+        self.sound_effects
+            .schedule(self.playback_time, schedule_end);
 
         for out in out.iter_mut() {
-            let next = match &mut self.music {
-                Some(music) if self.playing => music.next(),
-                _ => None,
+            let next = if self.playing {
+                self.music.next()
+            } else {
+                None
             };
             let mut next = next.unwrap_or(0.0);
             next *= self.music_volume as f32;
-            next += self.sound_effects.iter_mut().map(|x| x.next()).sum::<f64>() as f32;
+            next += self.sound_effects.next_sample() as f32;
             let next = next.clamp(-1.5, 1.5); // Prevent too large sound
             *out = S::from(&next);
         }
@@ -248,6 +612,7 @@ impl AudioOutputCallback {
             AudioState::Playing {
                 instant,
                 music_position: self.playback_time,
+                speed: self.speed,
             }
         } else {
             AudioState::NotPlaying
@@ -261,34 +626,203 @@ impl AudioOutputCallback {
             Play => self.playing = true,
             Pause => self.playing = false,
             Seek(time) => {
-                // TODO negative seek
-                self.sound_effect_schedules = Self::empty_schedules();
                 self.sound_effects.clear();
                 self.playback_time = time;
-                if let Some(music) = &mut self.music {
-                    music.seek(time.max(0.0)).unwrap();
-                }
                 self.playing = false;
+                self.request_seek();
             }
-            LoadMusic(path) => {
-                if let Err(e) = self.load_music(path) {
-                    eprintln!("{}", e);
+            SetVolume(vol) => self.music_volume = vol,
+            SetPlaybackSpeed(speed) => self.set_speed(speed),
+            SetSoundEffectSchedules(schedules) => self.sound_effects.set_schedules(schedules),
+            SetSoundEffectVolume(vol) => self.sound_effects.set_volume(vol),
+            SetLoop(region) => {
+                self.loop_region = region;
+                self.prepare_loop_music();
+            }
+        };
+    }
+
+    /// Sends the decoder to the music worker to be moved to the playback time. Does nothing if it
+    /// is already away, since it is checked against the playback time when it comes back.
+    fn request_seek(&mut self) {
+        let decoder = match self.music.get_mut().0.take() {
+            Some(decoder) => decoder,
+            None => return,
+        };
+        // Aim ahead of the playback, which goes on while seeking
+        let lead = if self.playing {
+            self.seek_lead * self.speed
+        } else {
+            0.0
+        };
+        let request = MusicRequest::Seek {
+            decoder,
+            time: (self.playback_time + lead).max(0.0),
+            generation: self.music_generation,
+            for_loop: false,
+        };
+        match self.music_requests.try_send(request) {
+            Ok(()) => {
+                self.music_needs_seek = false;
+                // Stop the output of the old position right away
+                self.music.reset(0);
+            }
+            Err(e) => {
+                if let MusicRequest::Seek { decoder, .. } = e.into_inner() {
+                    self.music.get_mut().0 = Some(decoder);
                 }
+                self.music_needs_seek = true;
             }
-            SetVolume(vol) => self.music_volume = vol,
-            SetSoundEffectSchedules(schedules) => {
-                self.sound_effect_schedules = schedules.peekable()
+        }
+    }
+
+    /// Plays the decoder if it is not behind the playback, or sends it to be seeked again.
+    fn receive_music(&mut self, music: PositionedMusic) {
+        if music.generation < self.music_generation {
+            self.discard(music.decoder);
+            return;
+        }
+        if music.for_loop {
+            self.loop_music_pending = false;
+            if let Some(old) = self.loop_music.replace(music) {
+                self.discard(old.decoder);
+            }
+            // The loop may have moved meanwhile
+            self.prepare_loop_music();
+            return;
+        }
+        if music.generation > self.music_generation {
+            // Newly loaded music. What is still at the worker comes back with the old generation.
+            self.music_generation = music.generation;
+            if let Some(old) = self.music.get_mut().0.take() {
+                self.discard(old);
+            }
+            if let Some(old) = self.loop_music.take() {
+                self.discard(old.decoder);
+            }
+            self.loop_music_pending = false;
+            self.prepare_loop_music();
+        } else if self.music.get_mut().0.is_some() {
+            self.discard(music.decoder);
+            return;
+        }
+
+        self.music.get_mut().0 = Some(music.decoder);
+        let max_lead = self.playback_time.max(0.0) + self.seek_lead * 2.0 * self.speed;
+        if self.playback_time <= music.time && music.time <= max_lead {
+            self.seek_lead = SEEK_LEAD;
+            // Before the music or the decoder, play silence until it starts
+            let sample_rate = self.output_stream_config.sample_rate.0 as f64;
+            let silence = ((music.time - self.playback_time) * sample_rate).round() as usize;
+            self.music.reset(silence);
+        } else {
+            if self.playing && music.time < self.playback_time {
+                self.seek_lead *= 2.0;
+            }
+            self.request_seek();
+        }
+    }
+
+    /// Frees the decoder on the music worker, or here if its queue is full.
+    fn discard(&self, decoder: MusicDecoder) {
+        let _ = self.music_requests.try_send(MusicRequest::Drop(decoder));
+    }
+
+    /// Keeps `loop_music` at the start of the loop, or drops it without a loop.
+    fn prepare_loop_music(&mut self) {
+        let start = match &self.loop_region {
+            Some(region) => region.start.max(0.0),
+            None => {
+                if let Some(music) = self.loop_music.take() {
+                    self.discard(music.decoder);
+                }
+                return;
+            }
+        };
+        if self.loop_music_pending || self.music_generation == 0 {
+            return;
+        }
+        let generation = self.music_generation;
+        let request = match self.loop_music.take() {
+            Some(music) if music.time == start => {
+                self.loop_music = Some(music);
+                return;
             }
-            SetSoundEffectVolume(vol) => self.sound_effect_volume = vol,
+            Some(music) => MusicRequest::Seek {
+                decoder: music.decoder,
+                time: start,
+                generation,
+                for_loop: true,
+            },
+            None => MusicRequest::OpenLoop {
+                time: start,
+                generation,
+            },
         };
+        // If the queue is full, the next jump seeks the playing decoder instead
+        self.loop_music_pending = self.music_requests.try_send(request).is_ok();
     }
 
-    fn load_music(&mut self, path: PathBuf) -> anyhow::Result<()> {
-        let file = std::fs::File::open(path)?;
-        let decoder = rodio::Decoder::new(BufReader::new(file))?;
-        let ret = new_uniform_source_iterator(decoder, &self.output_stream_config);
-        self.music = Some(ret);
-        Ok(())
+    /// Whether to jump back from the end of the loop now. Until `loop_music` is at the start, the
+    /// music goes on past the end for up to `LOOP_WAIT`, after which the jump seeks instead.
+    fn loop_music_ready(&mut self, end: f64) -> bool {
+        let start = match &self.loop_region {
+            Some(region) => region.start.max(0.0),
+            None => return true,
+        };
+        if matches!(&self.loop_music, Some(music) if music.time == start) {
+            return true;
+        }
+        self.prepare_loop_music();
+        !self.loop_music_pending || self.playback_time >= end + LOOP_WAIT * self.speed
+    }
+
+    /// Swaps in `loop_music` with a crossfade, and sends the decoder played so far to be moved to
+    /// the start for the next jump.
+    fn jump_to_loop_start(&mut self) {
+        let start = match &self.loop_region {
+            Some(region) => {
+                // The sound effects being played ring out
+                self.sound_effects.set_schedule_list(&region.schedules);
+                region.start
+            }
+            None => return,
+        };
+        self.playback_time = start;
+        let music = match self.loop_music.take() {
+            Some(music) if music.time == start.max(0.0) => music,
+            music => {
+                // It took too long or could not be requested, so the start is silent until the
+                // seek is done
+                self.loop_music = music;
+                self.request_seek();
+                return;
+            }
+        };
+        let played = self.music.get_mut().0.replace(music.decoder);
+        self.music_needs_seek = false;
+        // Before the start of the music, play silence until it starts
+        let sample_rate = self.output_stream_config.sample_rate.0 as f64;
+        let silence = ((music.time - start) * sample_rate).round() as usize;
+        self.music.reset_with_crossfade(silence);
+        match played {
+            Some(decoder) => {
+                let request = MusicRequest::Seek {
+                    decoder,
+                    time: music.time,
+                    generation: self.music_generation,
+                    for_loop: true,
+                };
+                self.loop_music_pending = self.music_requests.try_send(request).is_ok();
+            }
+            None => self.prepare_loop_music(),
+        }
+    }
+
+    fn set_speed(&mut self, speed: f64) {
+        self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+        self.sound_effects.set_speed(self.speed);
+        self.music.set_speed(self.speed);
     }
 
     fn into_callback<S>(mut self) -> impl FnMut(&mut [S], &OutputCallbackInfo) + Send + 'static
@@ -297,9 +831,4 @@ impl AudioOutputCallback {
     {
         move |a, b| self.callback(a, b)
     }
-
-    fn empty_schedules() -> Peekable<SESchedulesBox> {
-        let ret: SESchedulesBox = Box::new(iter::empty());
-        ret.peekable()
-    }
 }
@@ -0,0 +1,279 @@
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+#[cfg(feature = "audio")]
+use std::io::Cursor;
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use anyhow::Context;
+use clap::ArgEnum;
+use clap::Parser;
+use itertools::Itertools;
+use karaoke::config::Config;
+use karaoke::formats::ass::write_ass;
+use karaoke::formats::audacity::write_audacity_labels;
+use karaoke::formats::audacity::LabelKind;
+use karaoke::formats::json::write_json;
+use karaoke::formats::lrc::write_lrc;
+use karaoke::formats::midi::write_midi;
+use karaoke::formats::midi::MidiExportOptions;
+use karaoke::formats::musicxml::write_musicxml;
+use karaoke::formats::subtitles::write_srt;
+use karaoke::formats::subtitles::write_vtt;
+use karaoke::formats::ultrastar::write_ultrastar;
+use karaoke::formats::ultrastar::UltraStarMetadata;
+#[cfg(feature = "audio")]
+use karaoke::metronome::render_metronome;
+#[cfg(feature = "audio")]
+use karaoke::metronome::MetronomeRenderOptions;
+use karaoke::schema::MusicStatus;
+use karaoke::schema::Score;
+#[cfg(feature = "render")]
+use karaoke::video::write_png_sequence;
+#[cfg(feature = "render")]
+use karaoke::video::write_raw_frames;
+#[cfg(feature = "render")]
+use karaoke::video::FrameRenderer;
+
+#[derive(Clone, Copy, ArgEnum)]
+enum Format {
+    Lrc,
+    Ass,
+    Srt,
+    Vtt,
+    Ultrastar,
+    Midi,
+    Musicxml,
+    Audacity,
+    Json,
+    /// WAV file of the metronome
+    Metronome,
+    /// PNG frames of a karaoke video into the output directory, or raw RGBA frames to stdout
+    Frames,
+}
+
+/// The formats that are written as a single file, which can be matched on exhaustively once
+/// frames have been handled.
+#[derive(Clone, Copy)]
+enum FileFormat {
+    Lrc,
+    Ass,
+    Srt,
+    Vtt,
+    Ultrastar,
+    Midi,
+    Musicxml,
+    Audacity,
+    Json,
+    Metronome,
+}
+
+impl Format {
+    fn file_format(self) -> Option<FileFormat> {
+        Some(match self {
+            Format::Lrc => FileFormat::Lrc,
+            Format::Ass => FileFormat::Ass,
+            Format::Srt => FileFormat::Srt,
+            Format::Vtt => FileFormat::Vtt,
+            Format::Ultrastar => FileFormat::Ultrastar,
+            Format::Midi => FileFormat::Midi,
+            Format::Musicxml => FileFormat::Musicxml,
+            Format::Audacity => FileFormat::Audacity,
+            Format::Json => FileFormat::Json,
+            Format::Metronome => FileFormat::Metronome,
+            Format::Frames => return None,
+        })
+    }
+}
+
+#[derive(Parser)]
+#[clap(about = "Converts a karaoke project without opening the editor")]
+struct Args {
+    /// Project file to convert
+    project: PathBuf,
+    /// Output format
+    #[clap(short, long, arg_enum)]
+    format: Format,
+    /// Output file. Writes to stdout if omitted or `-`.
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+    /// Config file with the ASS, subtitle and video options. Defaults are used if omitted.
+    #[clap(short, long)]
+    config: Option<PathBuf>,
+    /// Song title, for UltraStar and MusicXML
+    #[clap(long, default_value = "")]
+    title: String,
+    /// Artist, for UltraStar
+    #[clap(long, default_value = "")]
+    artist: String,
+    /// Ticks per beat, for MIDI
+    #[clap(long, default_value = "480")]
+    ppq: u16,
+    /// Write a label per syllable instead of per track, for Audacity
+    #[clap(long)]
+    syllables: bool,
+    /// Volume of the clicks, for the metronome
+    #[clap(long, default_value = "0.4")]
+    metronome_volume: f64,
+    /// Mix the music of the project at this volume, for the metronome
+    #[clap(long)]
+    music_volume: Option<f64>,
+    /// Font file, overriding the one of the project, for frames
+    #[clap(long)]
+    font: Option<PathBuf>,
+    /// Time of the first frame in seconds, for frames
+    #[clap(long)]
+    start: Option<f64>,
+    /// Time after the last frame in seconds, for frames. Defaults to a second after the last
+    /// note.
+    #[clap(long)]
+    end: Option<f64>,
+    /// Check that the music still has the contents the project was timed against. This reads the
+    /// whole file; otherwise only its existence is checked.
+    #[clap(long)]
+    verify_music: bool,
+}
+
+fn main() {
+    if let Err(e) = run(Args::parse()) {
+        // Most errors in this crate display their source as their own message.
+        let messages = e.chain().map(|cause| cause.to_string()).dedup().join(": ");
+        eprintln!("error: {}", messages);
+        std::process::exit(1);
+    }
+}
+
+fn run(args: Args) -> anyhow::Result<()> {
+    let config = match &args.config {
+        Some(path) => Some(
+            Config::load(path)
+                .with_context(|| format!("failed to load config {}", path.display()))?,
+        ),
+        None => None,
+    };
+    let ass_options = config.as_ref().map(|c| c.ass.clone()).unwrap_or_default();
+    let subtitle_options = config
+        .as_ref()
+        .map(|c| c.subtitles.clone())
+        .unwrap_or_default();
+
+    let score = Score::load(&args.project)
+        .with_context(|| format!("failed to load project {}", args.project.display()))?;
+    let errors = score.validate();
+    if !errors.is_empty() {
+        for error in &errors {
+            eprintln!("{}", error);
+        }
+        bail!("the project has {} problem(s)", errors.len());
+    }
+    if let Some(music) = &score.music {
+        let status = if args.verify_music {
+            music.verify()
+        } else if music.path.exists() {
+            Ok(MusicStatus::Unchanged)
+        } else {
+            Ok(MusicStatus::Missing)
+        };
+        match status {
+            Ok(MusicStatus::Unchanged) => {}
+            Ok(MusicStatus::Changed) => eprintln!(
+                "warning: {} has changed since the project was timed",
+                music.path.display()
+            ),
+            Ok(MusicStatus::Missing) => {
+                eprintln!("warning: {} is missing", music.path.display())
+            }
+            Err(e) => eprintln!("warning: failed to read {}: {}", music.path.display(), e),
+        }
+    }
+
+    let format = match args.format.file_format() {
+        Some(format) => format,
+        None => return write_frames(&score, config, &args),
+    };
+
+    let mut out: Box<dyn Write> = match &args.output {
+        Some(path) if path.as_os_str() != "-" => {
+            Box::new(BufWriter::new(File::create(path).with_context(|| {
+                format!("failed to create {}", path.display())
+            })?))
+        }
+        _ => Box::new(BufWriter::new(io::stdout())),
+    };
+    match format {
+        FileFormat::Lrc => write_lrc(&score, &mut out)?,
+        FileFormat::Ass => write_ass(&score, &ass_options, &mut out)?,
+        FileFormat::Srt => write_srt(&score, &subtitle_options, &mut out)?,
+        FileFormat::Vtt => write_vtt(&score, &subtitle_options, &mut out)?,
+        FileFormat::Ultrastar => {
+            let metadata = UltraStarMetadata {
+                title: args.title,
+                artist: args.artist,
+                audio: None,
+            };
+            write_ultrastar(&score, &metadata, &mut out)?
+        }
+        FileFormat::Midi => {
+            let options = MidiExportOptions {
+                ppq: args.ppq,
+                ..Default::default()
+            };
+            write_midi(&score, &options, &mut out)?
+        }
+        FileFormat::Musicxml => write_musicxml(&score, &args.title, &mut out)?,
+        FileFormat::Audacity => {
+            let kind = if args.syllables {
+                LabelKind::Syllables
+            } else {
+                LabelKind::Tracks
+            };
+            write_audacity_labels(&score, kind, &mut out)?
+        }
+        FileFormat::Json => write_json(&score, &mut out)?,
+        #[cfg(feature = "audio")]
+        FileFormat::Metronome => {
+            let options = MetronomeRenderOptions {
+                metronome_volume: args.metronome_volume,
+                music_volume: args.music_volume,
+            };
+            // WAV headers are written last, so the output has to be seekable
+            let mut wav = Cursor::new(Vec::new());
+            render_metronome(&score, &options, &mut wav)?;
+            out.write_all(wav.get_ref())?;
+        }
+        #[cfg(not(feature = "audio"))]
+        FileFormat::Metronome => bail!("this build does not support audio"),
+    }
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(feature = "render")]
+fn write_frames(score: &Score, config: Option<Config>, args: &Args) -> anyhow::Result<()> {
+    let font_file = args.font.as_ref().unwrap_or(&score.font_file);
+    let font_data = std::fs::read(font_file)
+        .with_context(|| format!("failed to read font {}", font_file.display()))?;
+    let options = config.map(|c| c.video).unwrap_or_default();
+    let renderer = FrameRenderer::new(score, &font_data, options)
+        .with_context(|| format!("failed to render with font {}", font_file.display()))?;
+    let default_range = renderer.default_time_range();
+    let range = args.start.unwrap_or(default_range.start)..args.end.unwrap_or(default_range.end);
+    let count = match &args.output {
+        Some(directory) if directory.as_os_str() != "-" => {
+            std::fs::create_dir_all(directory)
+                .with_context(|| format!("failed to create {}", directory.display()))?;
+            write_png_sequence(&renderer, range, directory)?
+        }
+        _ => write_raw_frames(&renderer, range, BufWriter::new(io::stdout()))?,
+    };
+    let (width, height) = renderer.resolution();
+    eprintln!("wrote {} frames of {}x{}", count, width, height);
+    Ok(())
+}
+
+#[cfg(not(feature = "render"))]
+fn write_frames(_score: &Score, _config: Option<Config>, _args: &Args) -> anyhow::Result<()> {
+    bail!("this build does not support rendering")
+}
@@ -1,21 +1,50 @@
 use std::{
     fs::File,
     io::{BufReader, Read},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use serde::Deserialize;
 use thiserror::Error;
 
+use crate::formats::ass::AssOptions;
+use crate::formats::subtitles::SubtitleOptions;
+#[cfg(feature = "render")]
+use crate::video::VideoOptions;
+
 #[derive(Deserialize)]
 pub struct Config {
     pub font_path: PathBuf,
+    #[serde(default)]
+    pub ass: AssOptions,
+    #[serde(default)]
+    pub subtitles: SubtitleOptions,
+    #[cfg(feature = "render")]
+    #[serde(default)]
+    pub video: VideoOptions,
+    #[serde(default)]
+    pub playback: PlaybackOptions,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct PlaybackOptions {
+    /// Bar lines to go back from the cursor before playing, to hear the music leading to it.
+    pub pre_roll_measures: usize,
+}
+
+impl Default for PlaybackOptions {
+    fn default() -> Self {
+        Self {
+            pre_roll_measures: 2,
+        }
+    }
 }
 
 impl Config {
-    pub fn load() -> Result<Config, ConfigLoadError> {
+    pub fn load(path: impl AsRef<Path>) -> Result<Config, ConfigLoadError> {
         let mut s = String::new();
-        BufReader::new(File::open("config.toml")?).read_to_string(&mut s)?;
+        BufReader::new(File::open(path)?).read_to_string(&mut s)?;
         Ok(toml::from_str(&s)?)
     }
 }
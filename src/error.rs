@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use cpal::BuildStreamError;
 use cpal::PlayStreamError;
 use cpal::SupportedStreamConfigsError;
@@ -5,11 +7,20 @@ use druid::PlatformError;
 use thiserror::Error;
 
 use crate::config::ConfigLoadError;
+use crate::schema::ProjectLoadError;
 
 #[derive(Debug, Error)]
 pub enum EditorError {
     #[error("Error while loading config: {0}")]
     ConfigLoadError(#[from] ConfigLoadError),
+    #[error("Error while loading project: {0}")]
+    ProjectLoadError(#[from] ProjectLoadError),
+    #[error("{} does not exist. Pass --new to start a new project there.", .0.display())]
+    ProjectNotFound(PathBuf),
+    #[error("{} already exists. Omit --new to open it.", .0.display())]
+    ProjectExists(PathBuf),
+    #[error("Error while loading music: {0}")]
+    MusicLoadError(anyhow::Error),
     #[error("Error while initializing GUI widget: {0}")]
     DruidError(#[from] PlatformError),
     #[error("Error while initializing audio: {0}")]
@@ -2,12 +2,17 @@ use std::cmp::Eq;
 use std::collections::hash_map::Entry;
 use std::fs::File;
 use std::io::{BufReader, Read};
+#[cfg(feature = "gui")]
 use std::ops::DerefMut;
 use std::path::Path;
+use std::sync::Arc;
 use std::{collections::HashMap, path::PathBuf};
 
+#[cfg(feature = "gui")]
 use druid::piet::CoreGraphicsImage;
+#[cfg(feature = "gui")]
 use druid::PaintCtx;
+#[cfg(feature = "gui")]
 use druid::{piet::ImageFormat, RenderContext};
 use freetype::{face::LoadFlag, Bitmap, Library, RenderMode};
 use itertools::{zip, Itertools};
@@ -50,16 +55,50 @@ fn load_file_into_vec(path: impl AsRef<Path>) -> Result<Vec<u8>, FontLoadError>
     Ok(v)
 }
 
-#[derive(Debug, Error)]
+#[derive(Clone, Debug, Error)]
 pub enum FontLoadError {
     #[error("{0}")]
-    IOError(#[from] std::io::Error),
+    IOError(Arc<std::io::Error>),
+    #[error("not a TrueType or OpenType font")]
+    InvalidFont,
+    #[error("{0}")]
+    FreeTypeError(#[from] freetype::Error),
+}
+
+impl From<std::io::Error> for FontLoadError {
+    fn from(e: std::io::Error) -> Self {
+        Self::IOError(Arc::new(e))
+    }
+}
+
+#[cfg(feature = "gui")]
+#[derive(Clone, Debug, Error)]
+pub enum RenderTextError {
+    #[error("{0}")]
+    FontLoadError(#[from] FontLoadError),
+    #[error("{0}")]
+    ImageError(Arc<druid::piet::Error>),
 }
 
+/// 60pt at 50dpi, the size of the text in the editor
+pub const EDITOR_FONT_SIZE: f64 = 60.0 * 50.0 / 72.0;
+
+#[cfg(feature = "gui")]
 pub struct RenderedText {
     pub glyphs: Vec<RenderedGlyph>,
     pub image: CoreGraphicsImage,
 }
+
+/// Text rendered in white into a buffer of premultiplied RGBA pixels, row by row.
+pub struct RasterizedText {
+    pub glyphs: Vec<RenderedGlyph>,
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+    /// The cursor position after the last glyph
+    pub end_cursor_pos: (usize, usize),
+}
+
 #[derive(Debug)]
 pub struct RenderedGlyph {
     pub cursor_pos: (usize, usize),
@@ -69,58 +108,131 @@ pub struct RenderedGlyph {
     pub glyph_info: GlyphInfo,
 }
 
+#[cfg(feature = "gui")]
 impl RenderedText {
+    /// Uploads the pixels of the text as an image for drawing with `paint_ctx`.
+    pub fn new(paint_ctx: &mut PaintCtx, text: RasterizedText) -> Result<Self, RenderTextError> {
+        let image = paint_ctx
+            .make_image(
+                text.width,
+                text.height,
+                &text.pixels,
+                ImageFormat::RgbaPremul,
+            )
+            .map_err(|e| RenderTextError::ImageError(Arc::new(e)))?;
+        Ok(Self {
+            image,
+            glyphs: text.glyphs,
+        })
+    }
+
     pub fn is_boundary(&self, x: usize) -> bool {
-        x == 0
-            || x == self.glyphs.len()
-            || match (self.glyphs.get(x - 1), self.glyphs.get(x)) {
-                (Some(a), Some(b)) => a.glyph_info.cluster != b.glyph_info.cluster,
-                _ => false,
-            }
+        is_cluster_boundary(&self.glyphs, x)
     }
+
+    pub fn char_to_glyph_index(&self, text: &str, char_index: usize) -> usize {
+        char_to_glyph_index(&self.glyphs, text, char_index)
+    }
+
+    pub fn glyph_to_char_index(&self, text: &str, glyph_index: usize) -> usize {
+        glyph_to_char_index(&self.glyphs, text, glyph_index)
+    }
+}
+
+impl RasterizedText {
+    /// Whether a cursor between glyphs `x - 1` and `x` lies between clusters, i.e. doesn't split
+    /// a ligature or a character from its combining marks.
+    pub fn is_boundary(&self, x: usize) -> bool {
+        is_cluster_boundary(&self.glyphs, x)
+    }
+
+    /// The first glyph of the character at `char_index` of `text`, the text that was rendered.
+    /// A character inside a cluster, like the "i" of a "fi" ligature, maps to the next cluster.
+    pub fn char_to_glyph_index(&self, text: &str, char_index: usize) -> usize {
+        char_to_glyph_index(&self.glyphs, text, char_index)
+    }
+
+    /// The first character of the glyph at `glyph_index` of `text`, the text that was rendered.
+    pub fn glyph_to_char_index(&self, text: &str, glyph_index: usize) -> usize {
+        glyph_to_char_index(&self.glyphs, text, glyph_index)
+    }
+}
+
+// Glyph clusters are byte offsets into the text
+fn char_to_glyph_index(glyphs: &[RenderedGlyph], text: &str, char_index: usize) -> usize {
+    let offset = text
+        .char_indices()
+        .nth(char_index)
+        .map_or(text.len(), |(i, _)| i);
+    glyphs
+        .iter()
+        .position(|glyph| glyph.glyph_info.cluster as usize >= offset)
+        .unwrap_or(glyphs.len())
 }
 
+fn glyph_to_char_index(glyphs: &[RenderedGlyph], text: &str, glyph_index: usize) -> usize {
+    let offset = glyphs
+        .get(glyph_index)
+        .map_or(text.len(), |glyph| glyph.glyph_info.cluster as usize);
+    text[..offset].chars().count()
+}
+
+fn is_cluster_boundary(glyphs: &[RenderedGlyph], x: usize) -> bool {
+    x == 0
+        || x == glyphs.len()
+        || match (glyphs.get(x - 1), glyphs.get(x)) {
+            (Some(a), Some(b)) => a.glyph_info.cluster != b.glyph_info.cluster,
+            _ => false,
+        }
+}
+
+#[cfg(feature = "gui")]
 pub fn render_text(
     mut font_loader: impl DerefMut<Target = FontLoader>,
     font_path: PathBuf,
     paint_ctx: &mut PaintCtx,
     text: &str,
-) -> RenderedText {
-    // TODO remove unwraps!
+) -> Result<RenderedText, RenderTextError> {
+    let font_data = font_loader
+        .get(font_path, ForceLoad::False)
+        .map_err(Clone::clone)?;
+    RenderedText::new(
+        paint_ctx,
+        rasterize_text(font_data, text, EDITOR_FONT_SIZE)?,
+    )
+}
 
-    let font_data = font_loader.get(font_path, ForceLoad::False).unwrap();
+/// Shapes and renders the text with the font at `pixel_size` pixels per em, without a
+/// `PaintCtx`.
+pub fn rasterize_text(
+    font_data: &[u8],
+    text: &str,
+    pixel_size: f64,
+) -> Result<RasterizedText, FontLoadError> {
     let face_index = 0;
-    let hb_face = rustybuzz::Face::from_slice(font_data, face_index).unwrap();
+    let hb_face =
+        rustybuzz::Face::from_slice(font_data, face_index).ok_or(FontLoadError::InvalidFont)?;
 
     let mut buffer = UnicodeBuffer::new();
     // buffer.set_direction(rustybuzz::Direction::RightToLeft);
     buffer.push_str(text);
     let shape = rustybuzz::shape(&hb_face, &[], buffer);
 
-    let ft_lib = Library::init().unwrap();
-    let ft_face = ft_lib
-        .new_memory_face(font_data.to_owned(), face_index as isize) // TODO unnecessary copy?
-        .unwrap();
-    let font_size = 60;
-    let resolution = 50;
-    ft_face
-        .set_char_size(font_size * 64, 0, resolution, 0)
-        .unwrap();
+    let ft_lib = Library::init()?;
+    let ft_face = ft_lib.new_memory_face(font_data.to_owned(), face_index as isize)?; // TODO unnecessary copy?
+    ft_face.set_char_size((pixel_size * 64.0) as isize, 0, 72, 0)?;
 
     // TODO The buffer is too big
 
     let (mut x, mut y) = (0, 0);
-    let font_size_in_pixels = font_size as f64 * resolution as f64 / 72.0;
-    let hb_scale = font_size_in_pixels / ft_face.em_size() as f64;
+    let hb_scale = pixel_size / ft_face.em_size() as f64;
 
     let (xys, infos) = shape
         .glyph_positions()
         .iter()
         .zip(shape.glyph_infos())
         .map(|(pos, info)| {
-            ft_face
-                .load_glyph(info.codepoint, LoadFlag::DEFAULT)
-                .unwrap();
+            ft_face.load_glyph(info.codepoint, LoadFlag::DEFAULT)?;
             let glyph_slot = ft_face.glyph();
             let bitmap = glyph_slot.bitmap();
 
@@ -131,11 +243,13 @@ pub fn render_text(
             x += (pos.x_advance as f64 * hb_scale) as i32;
             y += (pos.y_advance as f64 * hb_scale) as i32;
 
-            (
+            Ok((
                 (old_xy, (draw_x, draw_y)),
                 ((bitmap.width(), bitmap.rows()), pos, info),
-            )
+            ))
         })
+        .collect::<Result<Vec<_>, FontLoadError>>()?
+        .into_iter()
         .unzip::<_, _, Vec<_>, Vec<_>>();
 
     let xs = zip(&xys, &infos)
@@ -163,12 +277,10 @@ pub fn render_text(
     let mut text_pixels = vec![0u8; 4 * w * h];
 
     for (&((_x, _y), (draw_x, draw_y)), (_, _, info)) in zip(&xys, &infos) {
-        ft_face
-            .load_glyph(info.codepoint, LoadFlag::DEFAULT)
-            .unwrap();
+        ft_face.load_glyph(info.codepoint, LoadFlag::DEFAULT)?;
         let glyph_slot = ft_face.glyph();
-        let glyph = glyph_slot.get_glyph().unwrap();
-        let inner_bitmap = glyph.to_bitmap(RenderMode::Normal, None).unwrap();
+        let glyph = glyph_slot.get_glyph()?;
+        let inner_bitmap = glyph.to_bitmap(RenderMode::Normal, None)?;
         let inner_bitmap = inner_bitmap.bitmap();
 
         blend_bitmap(
@@ -204,12 +316,13 @@ pub fn render_text(
             },
         )
         .collect();
-    RenderedText {
-        image: paint_ctx
-            .make_image(w, h, &text_pixels, ImageFormat::RgbaPremul)
-            .unwrap(),
+    Ok(RasterizedText {
         glyphs,
-    }
+        width: w,
+        height: h,
+        pixels: text_pixels,
+        end_cursor_pos: ((x - xs) as usize, (y - ys) as usize),
+    })
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -236,13 +349,7 @@ fn blend_bitmap(
             let src_a = bitmap.buffer()[i * bitmap.pitch() as usize + j] as f64;
             let k = y * pitch + x;
             let pixels = &mut pixels[k * 4..][..4];
-            for (p, c) in pixels[..3].iter_mut().zip(color.iter()) {
-                let res = *c as f64 * src_a + *p as f64 * (255.0 - src_a);
-                *p = (res / 255.0) as _;
-            }
-            let dst_a = &mut pixels[3];
-            let dst_aft = src_a + *dst_a as f64 * (255.0 - src_a);
-            *dst_a = dst_aft as _;
+            blend_pixel(pixels, src_a, color);
 
             // The following is for for drawing border, debugging purpose
             // if i == 0
@@ -258,3 +365,138 @@ fn blend_bitmap(
         }
     }
 }
+
+/// Draws `color` with alpha `src_a` over a premultiplied RGBA pixel.
+fn blend_pixel(pixel: &mut [u8], src_a: f64, color: [u8; 3]) {
+    for (p, c) in pixel[..3].iter_mut().zip(color.iter()) {
+        let res = *c as f64 * src_a + *p as f64 * (255.0 - src_a);
+        *p = (res / 255.0) as _;
+    }
+    let dst_a = &mut pixel[3];
+    let dst_aft = src_a + *dst_a as f64 * (255.0 - src_a) / 255.0;
+    *dst_a = dst_aft as _;
+}
+
+#[cfg(test)]
+mod test {
+    use itertools::Itertools;
+
+    use super::blend_pixel;
+    use super::rasterize_text;
+    use super::FontLoadError;
+
+    const FONT: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/FiraSans-Regular.ttf"
+    ));
+
+    fn clusters(text: &str) -> Vec<u32> {
+        rasterize_text(FONT, text, 48.0)
+            .unwrap()
+            .glyphs
+            .iter()
+            .map(|glyph| glyph.glyph_info.cluster)
+            .collect()
+    }
+
+    #[test]
+    fn test_rasterize_text() {
+        let text = rasterize_text(FONT, "Hello big world", 48.0).unwrap();
+        assert_eq!(text.pixels.len(), 4 * text.width * text.height);
+        assert_eq!(text.glyphs.len(), 15);
+        assert_eq!(text.glyphs[0].cursor_pos.0, 0);
+        assert!(text
+            .glyphs
+            .iter()
+            .tuple_windows()
+            .all(|(a, b)| a.cursor_pos.0 < b.cursor_pos.0 && a.cursor_pos.1 == b.cursor_pos.1));
+        assert_eq!(text.end_cursor_pos.0, text.width);
+        for glyph in &text.glyphs {
+            assert!(glyph.top_left.0 + glyph.size.0 <= text.width);
+            assert!(glyph.top_left.1 + glyph.size.1 <= text.height);
+        }
+        // Spaces have no bitmap
+        assert_eq!(text.glyphs[5].size, (0, 0));
+
+        let empty = rasterize_text(FONT, "", 48.0).unwrap();
+        assert!(empty.glyphs.is_empty() && empty.pixels.is_empty());
+
+        assert!(matches!(
+            rasterize_text(b"not a font", "Hello", 48.0),
+            Err(FontLoadError::InvalidFont)
+        ));
+    }
+
+    #[test]
+    fn test_clusters() {
+        // "fi" is a ligature
+        assert_eq!(clusters("office"), vec![0, 1, 2, 4, 5]);
+        // The combining acute accent composes with "e"
+        assert_eq!(clusters("e\u{301}t"), vec![0, 3]);
+        // ... but is a glyph of its own over "q"
+        let text = rasterize_text(FONT, "q\u{301}x", 48.0).unwrap();
+        assert_eq!(
+            text.glyphs
+                .iter()
+                .map(|glyph| glyph.glyph_info.cluster)
+                .collect_vec(),
+            vec![0, 0, 3]
+        );
+        assert_eq!(
+            (0..=3).filter(|&x| text.is_boundary(x)).collect_vec(),
+            vec![0, 2, 3]
+        );
+    }
+
+    #[test]
+    fn test_glyph_char_indices() {
+        let text = rasterize_text(FONT, "office", 48.0).unwrap();
+        assert_eq!(
+            (0..=5)
+                .map(|x| text.glyph_to_char_index("office", x))
+                .collect_vec(),
+            vec![0, 1, 2, 4, 5, 6]
+        );
+        // The "i" of the ligature goes to the next glyph
+        assert_eq!(
+            (0..=6)
+                .map(|x| text.char_to_glyph_index("office", x))
+                .collect_vec(),
+            vec![0, 1, 2, 3, 3, 4, 5]
+        );
+
+        let text = rasterize_text(FONT, "e\u{301}t", 48.0).unwrap();
+        assert_eq!(text.glyph_to_char_index("e\u{301}t", 1), 2);
+        assert_eq!(text.char_to_glyph_index("e\u{301}t", 2), 1);
+        assert_eq!(text.char_to_glyph_index("e\u{301}t", 3), 2);
+    }
+
+    #[test]
+    fn test_blend_pixel() {
+        let mut pixel = [0, 0, 0, 0];
+        blend_pixel(&mut pixel, 128.0, [255, 255, 255]);
+        assert_eq!(pixel, [128, 128, 128, 128]);
+        // The alpha covers the remaining 127 / 255 of the pixel by half
+        blend_pixel(&mut pixel, 128.0, [255, 255, 255]);
+        assert_eq!(pixel, [191, 191, 191, 191]);
+        blend_pixel(&mut pixel, 255.0, [255, 0, 0]);
+        assert_eq!(pixel, [255, 0, 0, 255]);
+
+        let mut pixel = [10, 20, 30, 40];
+        blend_pixel(&mut pixel, 0.0, [255, 255, 255]);
+        assert_eq!(pixel, [10, 20, 30, 40]);
+    }
+
+    #[test]
+    fn test_overlapping_glyphs() {
+        // The boxes of "A" and "V" overlap. White premultiplied pixels have every channel equal
+        // to the alpha.
+        let text = rasterize_text(FONT, "AV", 48.0).unwrap();
+        let (a, v) = (&text.glyphs[0], &text.glyphs[1]);
+        assert!(v.top_left.0 < a.top_left.0 + a.size.0);
+        assert!(text
+            .pixels
+            .chunks(4)
+            .all(|pixel| pixel[..3].iter().all(|&c| c == pixel[3])));
+    }
+}
@@ -0,0 +1,457 @@
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+
+use itertools::Itertools;
+use serde::de;
+use serde::Deserialize;
+use serde::Deserializer;
+use thiserror::Error;
+
+use super::quantize;
+use super::split_time;
+use super::TrackBuilder;
+use crate::schema::BeatLength;
+use crate::schema::Score;
+use crate::schema::Track;
+
+const STYLE_NAME: &str = "Karaoke";
+
+/// Style and layout of the exported subtitles.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct AssOptions {
+    /// Defaults to the file name of `Score.font_file`.
+    pub font_name: Option<String>,
+    pub font_size: u32,
+    /// Color of syllables that have been sung.
+    pub primary_color: AssColor,
+    /// Color of syllables that are yet to be sung.
+    pub secondary_color: AssColor,
+    pub outline_color: AssColor,
+    pub back_color: AssColor,
+    pub outline: f64,
+    pub shadow: f64,
+    /// Numpad-style alignment, e.g. 2 for bottom center.
+    pub alignment: u8,
+    pub margin_vertical: u32,
+    pub resolution: (u32, u32),
+    /// Fill syllables gradually (`\kf`) instead of at once (`\k`).
+    pub fill: bool,
+    /// Seconds a line is shown before its first note.
+    pub lead_in: f64,
+    /// Seconds a line is shown after its last note.
+    pub lead_out: f64,
+}
+
+impl Default for AssOptions {
+    fn default() -> Self {
+        Self {
+            font_name: None,
+            font_size: 48,
+            primary_color: AssColor::rgb(172, 255, 84),
+            secondary_color: AssColor::rgb(255, 255, 255),
+            outline_color: AssColor::rgb(0, 0, 0),
+            back_color: AssColor::rgba(0, 0, 0, 128),
+            outline: 2.0,
+            shadow: 0.0,
+            alignment: 2,
+            margin_vertical: 40,
+            resolution: (1280, 720),
+            fill: true,
+            lead_in: 1.0,
+            lead_out: 0.5,
+        }
+    }
+}
+
+/// A color written as `"#RRGGBB"` or `"#RRGGBBAA"` in the config, where alpha 255 is opaque.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct AssColor {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    pub alpha: u8,
+}
+
+impl AssColor {
+    pub const fn rgb(red: u8, green: u8, blue: u8) -> Self {
+        Self::rgba(red, green, blue, 255)
+    }
+
+    pub const fn rgba(red: u8, green: u8, blue: u8, alpha: u8) -> Self {
+        Self {
+            red,
+            green,
+            blue,
+            alpha,
+        }
+    }
+}
+
+/// ASS colors are `&HAABBGGRR`, with alpha counting transparency.
+impl fmt::Display for AssColor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "&H{:02X}{:02X}{:02X}{:02X}",
+            255 - self.alpha,
+            self.blue,
+            self.green,
+            self.red
+        )
+    }
+}
+
+impl FromStr for AssColor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("expected a color like \"#RRGGBB\", found {:?}", s);
+        let hex = s.strip_prefix('#').ok_or_else(error)?;
+        if !(hex.len() == 6 || hex.len() == 8) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(error());
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| error());
+        Ok(Self {
+            red: channel(0)?,
+            green: channel(2)?,
+            blue: channel(4)?,
+            alpha: if hex.len() == 8 { channel(6)? } else { 255 },
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for AssColor {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Writes each track with lyrics as a Dialogue event, with a `\k` or `\kf` tag per syllable.
+/// Gaps between notes become empty syllables.
+pub fn write_ass(score: &Score, options: &AssOptions, mut out: impl Write) -> io::Result<()> {
+    let font_name = match &options.font_name {
+        Some(name) => name.clone(),
+        None => score
+            .font_file
+            .file_stem()
+            .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned()),
+    };
+    writeln!(out, "[Script Info]")?;
+    writeln!(out, "ScriptType: v4.00+")?;
+    writeln!(out, "PlayResX: {}", options.resolution.0)?;
+    writeln!(out, "PlayResY: {}", options.resolution.1)?;
+    writeln!(out, "WrapStyle: 0")?;
+    writeln!(out, "ScaledBorderAndShadow: yes")?;
+    writeln!(out)?;
+    writeln!(out, "[V4+ Styles]")?;
+    writeln!(
+        out,
+        "Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, \
+         BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, \
+         BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding"
+    )?;
+    writeln!(
+        out,
+        "Style: {},{},{},{},{},{},{},0,0,0,0,100,100,0,0,1,{},{},{},10,10,{},1",
+        STYLE_NAME,
+        font_name.replace(',', " "),
+        options.font_size,
+        options.primary_color,
+        options.secondary_color,
+        options.outline_color,
+        options.back_color,
+        options.outline,
+        options.shadow,
+        options.alignment,
+        options.margin_vertical,
+    )?;
+    writeln!(out)?;
+    writeln!(out, "[Events]")?;
+    writeln!(
+        out,
+        "Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text"
+    )?;
+
+    let tag = if options.fill { "kf" } else { "k" };
+    // Times are rounded to centiseconds before taking differences so that errors do not add up.
+    let centis = |time: f64| (time.max(0.0) * 100.0).round() as u64;
+    for track in score
+        .tracks
+        .iter()
+        .sorted_by_key(|track| track.start_beat())
+    {
+        let syllables = track.syllables();
+        let (first_beat, last_beat) = match (
+            syllables.iter().find_map(|s| s.notes.first()),
+            syllables.iter().rev().find_map(|s| s.notes.last()),
+        ) {
+            (Some((first_beat, _)), Some((_, last_beat))) => (first_beat, last_beat),
+            _ => continue,
+        };
+        let start = centis(score.beat_to_time(first_beat) - options.lead_in);
+        let end = centis(score.beat_to_time(last_beat) + options.lead_out);
+
+        let mut text = String::new();
+        let mut cursor = start;
+        for syllable in &syllables {
+            let duration = match (syllable.notes.first(), syllable.notes.last()) {
+                (Some((start_beat, _)), Some((_, end_beat))) => {
+                    let syllable_start = centis(score.beat_to_time(start_beat));
+                    let syllable_end = centis(score.beat_to_time(end_beat));
+                    if syllable_start > cursor {
+                        text.push_str(&format!("{{\\k{}}}", syllable_start - cursor));
+                    }
+                    let syllable_start = syllable_start.max(cursor);
+                    cursor = syllable_end.max(syllable_start);
+                    cursor - syllable_start
+                }
+                _ => 0,
+            };
+            text.push_str(&format!("{{\\{}{}}}", tag, duration));
+            text.push_str(&syllable.text.replace("\r\n", "\\N").replace('\n', "\\N"));
+        }
+        writeln!(
+            out,
+            "Dialogue: 0,{},{},{},,0,0,0,karaoke,{}",
+            format_timestamp(start),
+            format_timestamp(end),
+            STYLE_NAME,
+            text
+        )?;
+    }
+    Ok(())
+}
+
+fn format_timestamp(centis: u64) -> String {
+    let (minutes, seconds, centis) = split_time(centis as f64 / 100.0, 100);
+    format!(
+        "{}:{:02}:{:02}.{:02}",
+        minutes / 60,
+        minutes % 60,
+        seconds,
+        centis
+    )
+}
+
+const DEFAULT_EVENT_FORMAT: [&str; 10] = [
+    "layer", "start", "end", "style", "name", "marginl", "marginr", "marginv", "effect", "text",
+];
+
+/// Reads the Dialogue events of an ASS file as tracks, one note per `\k`, `\kf` or `\ko`
+/// syllable. Times are converted with the tempo map of `score` and rounded to `grid`.
+/// Syllables consisting only of whitespace become rests.
+pub fn read_ass(
+    score: &Score,
+    input: &str,
+    grid: &BeatLength,
+) -> Result<Vec<Track>, AssImportError> {
+    let mut tracks = Vec::new();
+    let mut in_events = false;
+    let mut format = DEFAULT_EVENT_FORMAT
+        .iter()
+        .map(|&s| s.to_owned())
+        .collect_vec();
+    for (line_index, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_events = line.eq_ignore_ascii_case("[events]");
+            continue;
+        }
+        let (key, value) = match line.split_once(':') {
+            Some(x) if in_events => x,
+            _ => continue,
+        };
+        match key.trim() {
+            "Format" => {
+                format = value
+                    .split(',')
+                    .map(|s| s.trim().to_ascii_lowercase())
+                    .collect();
+            }
+            "Dialogue" => {
+                let error = |message: &str| {
+                    AssImportError::InvalidDialogue(line_index + 1, message.to_owned())
+                };
+                let fields = value.splitn(format.len(), ',').collect_vec();
+                let field = |name: &str| {
+                    format
+                        .iter()
+                        .position(|s| s == name)
+                        .and_then(|i| fields.get(i))
+                        .ok_or_else(|| error(&format!("missing field {:?}", name)))
+                };
+                let start =
+                    parse_timestamp(field("start")?).ok_or_else(|| error("invalid start time"))?;
+                let end =
+                    parse_timestamp(field("end")?).ok_or_else(|| error("invalid end time"))?;
+                let text = field("text")?;
+
+                let beat = |time: f64| quantize(score.time_to_beat(time), grid);
+                let mut builder = TrackBuilder::new(grid.clone());
+                let syllables = parse_karaoke_text(text);
+                if syllables.iter().all(|(duration, _)| duration.is_none()) {
+                    let text = syllables.into_iter().map(|(_, text)| text).join("");
+                    builder.push_syllable(&text, beat(start), beat(end));
+                } else {
+                    let mut time = start;
+                    for (duration, text) in syllables {
+                        match duration {
+                            Some(duration) if !text.trim().is_empty() => {
+                                builder.push_syllable(&text, beat(time), beat(time + duration));
+                                time += duration;
+                            }
+                            Some(duration) => {
+                                builder.push_text(&text);
+                                time += duration;
+                            }
+                            None => builder.push_text(&text),
+                        }
+                    }
+                }
+                tracks.extend(builder.build());
+            }
+            _ => {}
+        }
+    }
+    Ok(tracks)
+}
+
+#[derive(Debug, Error)]
+pub enum AssImportError {
+    #[error("line {0}: {1}")]
+    InvalidDialogue(usize, String),
+}
+
+/// Parses `H:MM:SS.cc` into seconds.
+fn parse_timestamp(s: &str) -> Option<f64> {
+    let mut parts = s.trim().split(':');
+    let hours: f64 = parts.next()?.parse().ok()?;
+    let minutes: f64 = parts.next()?.parse().ok()?;
+    let seconds: f64 = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(hours * 3600.0 + minutes * 60.0 + seconds)
+}
+
+/// Splits dialogue text at karaoke tags into syllables with their durations in seconds.
+/// Text before the first tag has no duration. Other override tags are dropped.
+fn parse_karaoke_text(text: &str) -> Vec<(Option<f64>, String)> {
+    let mut syllables = vec![(None, String::new())];
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => {
+                let block = chars.by_ref().take_while(|&c| c != '}').collect::<String>();
+                for tag in block.split('\\').skip(1) {
+                    let tag = tag.trim();
+                    let duration = tag
+                        .strip_prefix("kf")
+                        .or_else(|| tag.strip_prefix("ko"))
+                        .or_else(|| tag.strip_prefix('k'))
+                        .or_else(|| tag.strip_prefix('K'))
+                        .and_then(|centis| centis.trim().parse::<f64>().ok());
+                    if let Some(centis) = duration {
+                        syllables.push((Some(centis.max(0.0) / 100.0), String::new()));
+                    }
+                }
+            }
+            '\\' => {
+                let text = &mut syllables.last_mut().unwrap().1;
+                match chars.clone().next() {
+                    Some('N') => text.push('\n'),
+                    Some('n') | Some('h') => text.push(' '),
+                    _ => {
+                        text.push('\\');
+                        continue;
+                    }
+                }
+                chars.next();
+            }
+            c => syllables.last_mut().unwrap().1.push(c),
+        }
+    }
+    if syllables[0].1.is_empty() {
+        syllables.remove(0);
+    }
+    syllables
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test_util::sample_score;
+    use super::super::test_util::variable_score;
+    use super::read_ass;
+    use super::write_ass;
+    use super::AssColor;
+    use super::AssOptions;
+    use crate::schema::BeatLength;
+
+    #[test]
+    fn test_write_ass() {
+        let mut out = Vec::new();
+        write_ass(&sample_score(), &AssOptions::default(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Style: Karaoke,font,48,&H0054FFAC,&H00FFFFFF,"));
+        assert!(out.ends_with(
+            "Dialogue: 0,0:00:00.00,0:00:02.50,Karaoke,,0,0,0,karaoke,\
+             {\\kf50}Hello{\\kf50} big{\\k50}{\\kf50} world\n"
+        ));
+    }
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!("#102030".parse(), Ok(AssColor::rgb(16, 32, 48)));
+        assert_eq!(
+            "#10203040".parse::<AssColor>().unwrap().to_string(),
+            "&HBF302010"
+        );
+        assert!("102030".parse::<AssColor>().is_err());
+    }
+
+    #[test]
+    fn test_read_ass() {
+        let score = sample_score();
+        let mut ass = Vec::new();
+        write_ass(&score, &AssOptions::default(), &mut ass).unwrap();
+        let grid = BeatLength::one();
+        let tracks = read_ass(&score, &String::from_utf8(ass).unwrap(), &grid).unwrap();
+        assert_eq!(tracks.len(), 1);
+        let (original, imported) = (&score.tracks[0], &tracks[0]);
+        assert_eq!(imported.start_beat, original.start_beat);
+        assert_eq!(imported.elements, original.elements);
+        assert_eq!(imported.syllables(), original.syllables());
+    }
+
+    #[test]
+    fn test_write_ass_variable_tempo() {
+        // The rest before "très" is a plain \k, and the note held across the tempo change lasts
+        // half a second and then a second
+        let mut ass = Vec::new();
+        write_ass(&variable_score(), &AssOptions::default(), &mut ass).unwrap();
+        assert!(String::from_utf8(ass).unwrap().ends_with(
+            "Dialogue: 0,0:00:00.00,0:00:04.50,Karaoke,,0,0,0,karaoke,\
+             {\\kf50}Ça{\\kf50} va{\\k50}{\\kf150} très{\\kf100} bien\n"
+        ));
+    }
+
+    #[test]
+    fn test_read_ass_variable_tempo() {
+        let score = variable_score();
+        let mut ass = Vec::new();
+        write_ass(&score, &AssOptions::default(), &mut ass).unwrap();
+        let grid = BeatLength::one();
+        let tracks = read_ass(&score, &String::from_utf8(ass).unwrap(), &grid).unwrap();
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].syllables(), score.tracks[0].syllables());
+        assert_eq!(
+            tracks[0].lyrics.as_ref().unwrap().text,
+            score.tracks[0].lyrics.as_ref().unwrap().text
+        );
+    }
+}
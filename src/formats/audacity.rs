@@ -0,0 +1,172 @@
+use std::io;
+use std::io::Write;
+
+use itertools::Itertools;
+use thiserror::Error;
+
+use super::quantize;
+use super::TrackBuilder;
+use crate::schema::BeatLength;
+use crate::schema::Score;
+use crate::schema::Track;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LabelKind {
+    /// A label per track, spanning its notes
+    Tracks,
+    /// A label per mapped syllable, spanning its notes
+    Syllables,
+}
+
+#[derive(Debug, Error)]
+pub enum AudacityImportError {
+    #[error("line {0}: {1}")]
+    InvalidLabel(usize, String),
+}
+
+/// Writes an Audacity label track, one `start\tend\ttext` line per label.
+pub fn write_audacity_labels(
+    score: &Score,
+    kind: LabelKind,
+    mut out: impl Write,
+) -> io::Result<()> {
+    let mut labels = Vec::new();
+    for track in score.tracks.iter() {
+        let syllables = track
+            .syllables()
+            .into_iter()
+            .filter(|syllable| !syllable.notes.is_empty())
+            .collect_vec();
+        match kind {
+            LabelKind::Tracks => {
+                if let (Some(first), Some(last)) = (syllables.first(), syllables.last()) {
+                    let text = syllables.iter().map(|syllable| syllable.text).join("");
+                    let (start, _) = &first.notes[0];
+                    let (_, end) = &last.notes[last.notes.len() - 1];
+                    labels.push((start.clone(), end.clone(), text));
+                }
+            }
+            LabelKind::Syllables => {
+                for syllable in &syllables {
+                    let (start, _) = &syllable.notes[0];
+                    let (_, end) = &syllable.notes[syllable.notes.len() - 1];
+                    labels.push((start.clone(), end.clone(), syllable.text.to_owned()));
+                }
+            }
+        }
+    }
+    labels.sort_by(|x, y| x.0.cmp(&y.0));
+    for (start, end, text) in labels {
+        writeln!(
+            out,
+            "{:.6}\t{:.6}\t{}",
+            score.beat_to_time(&start),
+            score.beat_to_time(&end),
+            text.split_whitespace().join(" ")
+        )?;
+    }
+    Ok(())
+}
+
+/// Reads an Audacity label track as tracks with a note per label, with times converted by the
+/// tempo map of `score` and rounded to `grid`. The label text becomes the lyrics of the note.
+pub fn read_audacity_labels(
+    score: &Score,
+    input: &str,
+    grid: &BeatLength,
+) -> Result<Vec<Track>, AudacityImportError> {
+    let mut tracks = Vec::new();
+    for (line_index, line) in input.lines().enumerate() {
+        // Lines starting with a backslash hold the frequency range of the previous label.
+        if line.trim().is_empty() || line.starts_with('\\') {
+            continue;
+        }
+        let error =
+            |message: &str| AudacityImportError::InvalidLabel(line_index + 1, message.to_owned());
+        let mut fields = line.splitn(3, '\t');
+        let mut time = |name: &str| {
+            fields
+                .next()
+                .and_then(|s| s.trim().parse::<f64>().ok())
+                .ok_or_else(|| error(&format!("invalid {} time", name)))
+        };
+        let start = time("start")?;
+        let end = time("end")?;
+        let text = fields.next().unwrap_or("").trim();
+
+        let mut builder = TrackBuilder::new(grid.clone());
+        let start = quantize(score.time_to_beat(start), grid);
+        let end = quantize(score.time_to_beat(end), grid);
+        builder.push_syllable(text, start, end);
+        tracks.extend(builder.build());
+    }
+    Ok(tracks)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test_util::sample_score;
+    use super::super::test_util::variable_score;
+    use super::read_audacity_labels;
+    use super::write_audacity_labels;
+    use super::LabelKind;
+    use crate::schema::BeatLength;
+
+    #[test]
+    fn test_write_labels() {
+        let score = sample_score();
+        let mut out = Vec::new();
+        write_audacity_labels(&score, LabelKind::Syllables, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "0.000000\t0.500000\tHello\n\
+             0.500000\t1.000000\tbig\n\
+             1.500000\t2.000000\tworld\n"
+        );
+    }
+
+    #[test]
+    fn test_read_labels() {
+        let score = sample_score();
+        let mut out = Vec::new();
+        write_audacity_labels(&score, LabelKind::Tracks, &mut out).unwrap();
+        let input = String::from_utf8(out).unwrap() + "\\\t100.0\t200.0\n";
+        let tracks = read_audacity_labels(&score, &input, &BeatLength::one()).unwrap();
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].start_beat, score.tracks[0].start_beat);
+        assert_eq!(tracks[0].end_beat(), score.tracks[0].end_beat());
+        let syllables = tracks[0].syllables();
+        assert_eq!(syllables.len(), 1);
+        assert_eq!(syllables[0].text, "Hello big world");
+        assert_eq!(syllables[0].notes.len(), 1);
+    }
+
+    #[test]
+    fn test_labels_variable_tempo() {
+        let score = variable_score();
+        let mut out = Vec::new();
+        write_audacity_labels(&score, LabelKind::Syllables, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(
+            out,
+            "0.000000\t0.500000\tÇa\n\
+             0.500000\t1.000000\tva\n\
+             1.500000\t3.000000\ttrès\n\
+             3.000000\t4.000000\tbien\n"
+        );
+
+        // Each label is read back on the beats it was written from
+        let tracks = read_audacity_labels(&score, &out, &BeatLength::one()).unwrap();
+        let notes = tracks
+            .iter()
+            .flat_map(|track| track.syllables())
+            .map(|syllable| (syllable.text, syllable.notes))
+            .collect::<Vec<_>>();
+        let expected = score.tracks[0]
+            .syllables()
+            .into_iter()
+            .map(|syllable| (syllable.text.trim(), syllable.notes))
+            .collect::<Vec<_>>();
+        assert_eq!(notes, expected);
+    }
+}
@@ -0,0 +1,204 @@
+use std::io;
+use std::io::Write;
+use std::iter;
+
+use itertools::Itertools;
+use serde::Serialize;
+
+use crate::schema::iterate_beat_times;
+use crate::schema::iterate_measures;
+use crate::schema::BeatPosition;
+use crate::schema::Bpm;
+use crate::schema::Score;
+use crate::schema::Track;
+
+/// Bumped whenever a field is removed or changes meaning. Adding fields keeps the version.
+pub const JSON_FORMAT_VERSION: u32 = 1;
+
+/// The root of the JSON timing export. Beats are written as exact fractions in strings such
+/// as `"7/2"` or `"4"`, and times as seconds from the start of the music.
+#[derive(Clone, Debug, Serialize)]
+pub struct JsonTiming {
+    /// Always `JSON_FORMAT_VERSION`.
+    pub version: u32,
+    /// Time of beat 0.
+    pub offset: f64,
+    /// Each segment lasts until the next one starts, and the last one forever.
+    pub tempo: Vec<JsonTempoSegment>,
+    /// Consecutive measures from beat 0, covering every note.
+    pub measures: Vec<JsonMeasure>,
+    /// Every beat within `measures`.
+    pub beats: Vec<JsonBeat>,
+    pub tracks: Vec<JsonTrack>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct JsonTempoSegment {
+    pub start_beat: String,
+    pub start_time: f64,
+    pub bpm: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct JsonMeasure {
+    pub start_beat: String,
+    pub end_beat: String,
+    pub start_time: f64,
+    pub end_time: f64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct JsonBeat {
+    pub time: f64,
+    /// Whether the beat starts a measure.
+    pub downbeat: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct JsonTrack {
+    /// The whole lyrics of the track, empty if it has none.
+    pub text: String,
+    pub notes: Vec<JsonNote>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct JsonNote {
+    pub start_beat: String,
+    pub end_beat: String,
+    pub start_time: f64,
+    pub end_time: f64,
+    /// The part of `JsonTrack.text` sung from this note, including surrounding spaces. Notes
+    /// continuing the syllable of the previous note, and unmapped notes, have an empty lyric.
+    pub lyric: String,
+}
+
+impl JsonTiming {
+    pub fn new(score: &Score) -> Self {
+        let end = score
+            .tracks
+            .iter()
+            .map(Track::end_beat)
+            .max()
+            .unwrap_or_else(BeatPosition::zero);
+
+        let tempo = if score.bpms.is_empty() {
+            vec![(BeatPosition::zero(), Bpm::default())]
+        } else {
+            score
+                .bpms
+                .iter()
+                .map(|(beat, bpm)| (beat.clone(), *bpm))
+                .collect()
+        };
+        let tempo = tempo
+            .into_iter()
+            .map(|(beat, bpm)| JsonTempoSegment {
+                start_time: score.beat_to_time(&beat),
+                start_beat: beat.to_string(),
+                bpm: bpm.0,
+            })
+            .collect();
+
+        let measures = iterate_measures(score.measure_lengths.iter())
+            .enumerate()
+            .take_while(|(i, (start, _))| *i == 0 || start < &end)
+            .map(|(_, (start, end))| JsonMeasure {
+                start_time: score.beat_to_time(&start),
+                end_time: score.beat_to_time(&end),
+                start_beat: start.to_string(),
+                end_beat: end.to_string(),
+            })
+            .collect_vec();
+        let end_time = measures.last().map_or(score.offset, |m| m.end_time);
+        let beats = iterate_beat_times(
+            score.offset,
+            score.measure_lengths.clone(),
+            score.bpms.clone(),
+            BeatPosition::zero(),
+        )
+        .take_while(|(_, time)| *time < end_time)
+        .map(|(downbeat, time)| JsonBeat { time, downbeat })
+        .collect();
+
+        let tracks = score
+            .tracks
+            .iter()
+            .sorted_by_key(|track| track.start_beat())
+            .map(|track| json_track(score, track))
+            .collect();
+
+        Self {
+            version: JSON_FORMAT_VERSION,
+            offset: score.offset,
+            tempo,
+            measures,
+            beats,
+            tracks,
+        }
+    }
+}
+
+fn json_track(score: &Score, track: &Track) -> JsonTrack {
+    let lyrics = track.syllables().into_iter().flat_map(|syllable| {
+        let count = syllable.notes.len();
+        iter::once(syllable.text)
+            .chain(iter::repeat(""))
+            .take(count)
+    });
+    let notes = track
+        .iterate_notes()
+        .zip(lyrics.chain(iter::repeat("")))
+        .map(|((start, end, _), lyric)| JsonNote {
+            start_time: score.beat_to_time(&start),
+            end_time: score.beat_to_time(&end),
+            start_beat: start.to_string(),
+            end_beat: end.to_string(),
+            lyric: lyric.to_owned(),
+        })
+        .collect();
+    JsonTrack {
+        text: track
+            .lyrics
+            .as_ref()
+            .map_or_else(String::new, |lyrics| lyrics.text.clone()),
+        notes,
+    }
+}
+
+/// Writes the timing of the score as pretty-printed JSON in the schema of `JsonTiming`.
+pub fn write_json(score: &Score, out: impl Write) -> io::Result<()> {
+    serde_json::to_writer_pretty(out, &JsonTiming::new(score))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test_util::sample_score;
+    use super::JsonTiming;
+
+    #[test]
+    fn test_json_timing() {
+        let score = sample_score();
+        let timing = JsonTiming::new(&score);
+        assert_eq!(timing.version, 1);
+        assert_eq!(timing.tempo.len(), 1);
+        assert_eq!(timing.tempo[0].start_beat, "0");
+        assert_eq!(timing.measures.len(), 1);
+        assert_eq!(timing.measures[0].end_beat, "4");
+        assert_eq!(timing.beats.len(), 4);
+        assert!(timing.beats[0].downbeat && !timing.beats[1].downbeat);
+
+        let notes = &timing.tracks[0].notes;
+        assert_eq!(
+            notes
+                .iter()
+                .map(|note| (note.start_beat.as_str(), note.lyric.as_str()))
+                .collect::<Vec<_>>(),
+            vec![("0", "Hello"), ("1", " big"), ("3", " world")]
+        );
+        assert!((notes[2].end_time - 2.0).abs() < 1e-9);
+
+        let json = serde_json::to_value(&timing).unwrap();
+        assert_eq!(json["tracks"][0]["text"], "Hello big world");
+    }
+}
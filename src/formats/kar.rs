@@ -0,0 +1,282 @@
+use std::path::PathBuf;
+
+use itertools::Itertools;
+use midly::MetaMessage;
+use midly::Smf;
+use midly::TrackEvent;
+use midly::TrackEventKind;
+
+use super::midi::events_with_ticks;
+use super::midi::ppq;
+use super::midi::read_tempo_map;
+use super::midi::tick_to_beat;
+use super::midi::MidiError;
+use super::TrackBuilder;
+use crate::schema::BeatLength;
+use crate::schema::Score;
+
+/// Reads a Soft Karaoke file. The tempo map comes from the MIDI events, and each line of the
+/// lyrics becomes a track with a note per syllable, lasting until the next syllable and at
+/// most a beat at the end of a line.
+/// Syllables are the Text events of the lyrics track, or its Lyric events if there are none. A
+/// leading `/` starts a new line, `\` a new paragraph, and events starting with `@` are headers.
+/// The lyrics track is the one named "Words" or holding the headers. Without one, the syllables
+/// are taken from the track with the most Lyric events, or else the most Text events, so that
+/// names and comments in other tracks aren't sung.
+pub fn read_kar(input: &[u8], font_file: PathBuf) -> Result<Score, MidiError> {
+    let smf = Smf::parse(input)?;
+    let ppq = ppq(&smf)?;
+    let mut score = Score::new(font_file);
+    read_tempo_map(&smf, &mut score)?;
+
+    let syllables = match lyrics_track(&smf) {
+        Some(events) => {
+            let syllables = lyric_events(events, text_event);
+            if syllables.is_empty() {
+                lyric_events(events, lyric_event)
+            } else {
+                syllables
+            }
+        }
+        None => [lyric_event, text_event]
+            .iter()
+            .map(|&text| {
+                smf.tracks
+                    .iter()
+                    .map(|events| lyric_events(events, text))
+                    .max_by_key(Vec::len)
+                    .unwrap_or_default()
+            })
+            .find(|syllables| !syllables.is_empty())
+            .unwrap_or_default(),
+    };
+
+    // Lines of (tick, syllable), and whether each line starts a paragraph
+    let mut lines: Vec<(bool, Vec<(u64, String)>)> = Vec::new();
+    for (tick, text) in syllables {
+        let (new_line, text) = match text.chars().next() {
+            Some('\\') => (Some(true), text[1..].to_owned()),
+            Some('/') => (Some(false), text[1..].to_owned()),
+            _ => (None, text),
+        };
+        match (new_line, lines.last_mut()) {
+            (None, Some((_, line))) => line.push((tick, text)),
+            (paragraph, _) => lines.push((paragraph.unwrap_or(false), vec![(tick, text)])),
+        }
+    }
+
+    let line_starts = lines
+        .iter()
+        .map(|(_, line)| line[0].0)
+        .skip(1)
+        .chain(std::iter::once(u64::MAX))
+        .collect_vec();
+    let beat = u64::from(ppq);
+    for ((_, line), next_line_start) in lines.iter().zip(line_starts) {
+        let mut builder = TrackBuilder::new(BeatLength::one());
+        for (i, (tick, text)) in line.iter().enumerate() {
+            let end = match line.get(i + 1) {
+                Some((next_tick, _)) => *next_tick,
+                None => next_line_start.min(tick + beat),
+            };
+            if text.trim().is_empty() {
+                builder.push_text(text);
+            } else {
+                builder.push_syllable(text, tick_to_beat(*tick, ppq), tick_to_beat(end, ppq));
+            }
+        }
+        score.tracks.extend(builder.build());
+    }
+    score.lyrics = lines
+        .iter()
+        .map(|(paragraph, line)| {
+            let text = line.iter().map(|(_, text)| text).join("");
+            let separator = if *paragraph { "\n" } else { "" };
+            format!("{}{}", separator, text.trim())
+        })
+        .join("\n")
+        .trim()
+        .to_owned();
+    Ok(score)
+}
+
+/// The Soft Karaoke lyrics track, named "Words" or holding the `@` headers.
+fn lyrics_track<'a, 'b>(smf: &'b Smf<'a>) -> Option<&'b [TrackEvent<'a>]> {
+    let is_words = |event: &TrackEvent<'_>| match event.kind {
+        TrackEventKind::Meta(MetaMessage::TrackName(name)) => name == b"Words",
+        _ => false,
+    };
+    let is_header = |event: &TrackEvent<'_>| match event.kind {
+        TrackEventKind::Meta(MetaMessage::Text(text)) => text.starts_with(b"@"),
+        _ => false,
+    };
+    smf.tracks
+        .iter()
+        .find(|events| events.iter().any(is_words))
+        .or_else(|| {
+            smf.tracks
+                .iter()
+                .find(|events| events.iter().any(is_header))
+        })
+        .map(|events| &events[..])
+}
+
+fn text_event(message: MetaMessage<'_>) -> Option<&[u8]> {
+    match message {
+        MetaMessage::Text(text) => Some(text),
+        _ => None,
+    }
+}
+
+fn lyric_event(message: MetaMessage<'_>) -> Option<&[u8]> {
+    match message {
+        MetaMessage::Lyric(text) => Some(text),
+        _ => None,
+    }
+}
+
+/// Collects the non-header events of the track selected by `text`, ordered by tick.
+fn lyric_events<'a>(
+    events: &[TrackEvent<'a>],
+    text: fn(MetaMessage<'a>) -> Option<&'a [u8]>,
+) -> Vec<(u64, String)> {
+    events_with_ticks(events)
+        .filter_map(|(tick, kind)| match kind {
+            TrackEventKind::Meta(message) => Some((tick, decode(text(message)?))),
+            _ => None,
+        })
+        .filter(|(_, text)| !text.starts_with('@'))
+        .collect()
+}
+
+/// Karaoke files predate UTF-8 and are usually Latin-1.
+fn decode(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_owned(),
+        Err(_) => bytes.iter().map(|&b| char::from(b)).collect(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use midly::num::u15;
+    use midly::num::u24;
+    use midly::num::u28;
+    use midly::Format;
+    use midly::Header;
+    use midly::MetaMessage;
+    use midly::Smf;
+    use midly::Timing;
+    use midly::TrackEvent;
+    use midly::TrackEventKind;
+
+    use super::read_kar;
+
+    #[test]
+    fn test_read_kar() {
+        let words: &[(u32, &[u8])] = &[
+            (0, b"@KMIDI KARAOKE FILE"),
+            (0, b"@TTitle"),
+            (0, b"\\Hel"),
+            (48, b"lo "),
+            (48, b"world"),
+            (96, b"/Caf\xe9"),
+        ];
+        let mut smf = Smf::new(Header::new(
+            Format::Parallel,
+            Timing::Metrical(u15::new(96)),
+        ));
+        // Text outside of the lyrics track is not sung
+        let meta = |delta, message| TrackEvent {
+            delta: u28::new(delta),
+            kind: TrackEventKind::Meta(message),
+        };
+        smf.tracks.push(vec![
+            meta(0, MetaMessage::Copyright(b"(c) Someone")),
+            meta(0, MetaMessage::Text(b"Sequenced by someone")),
+            meta(0, MetaMessage::EndOfTrack),
+        ]);
+        smf.tracks.push(vec![
+            meta(0, MetaMessage::TrackName(b"Piano")),
+            meta(0, MetaMessage::Text(b"Grand piano")),
+            meta(0, MetaMessage::EndOfTrack),
+        ]);
+        smf.tracks.push(
+            words
+                .iter()
+                .map(|&(delta, text)| TrackEvent {
+                    delta: u28::new(delta),
+                    kind: TrackEventKind::Meta(MetaMessage::Text(text)),
+                })
+                .collect(),
+        );
+        let mut bytes = Vec::new();
+        smf.write_std(&mut bytes).unwrap();
+
+        let score = read_kar(&bytes, "font.otf".into()).unwrap();
+        assert_eq!(score.lyrics, "Hello world\nCafé");
+        assert_eq!(score.tracks.len(), 2);
+        let syllables = score.tracks[0].syllables();
+        assert_eq!(
+            syllables.iter().map(|s| s.text).collect::<Vec<_>>(),
+            vec!["Hel", "lo", " world"]
+        );
+        assert_eq!(
+            syllables.iter().map(|s| s.notes.len()).collect::<Vec<_>>(),
+            vec![1, 1, 1]
+        );
+        assert_eq!(
+            score.tracks[1].start_beat.0,
+            num::BigRational::from_integer(2.into())
+        );
+    }
+
+    #[test]
+    fn test_read_kar_tempo_map() {
+        let meta = |delta, message| TrackEvent {
+            delta: u28::new(delta),
+            kind: TrackEventKind::Meta(message),
+        };
+        let mut smf = Smf::new(Header::new(
+            Format::Parallel,
+            Timing::Metrical(u15::new(96)),
+        ));
+        // Slows from 120 to 60 BPM and changes from 4/4 to 3/4 at beat 4
+        smf.tracks.push(vec![
+            meta(0, MetaMessage::Tempo(u24::new(500_000))),
+            meta(0, MetaMessage::TimeSignature(4, 2, 24, 8)),
+            meta(384, MetaMessage::Tempo(u24::new(1_000_000))),
+            meta(0, MetaMessage::TimeSignature(3, 2, 24, 8)),
+            meta(0, MetaMessage::EndOfTrack),
+        ]);
+        smf.tracks.push(vec![
+            meta(0, MetaMessage::Text(b"@KMIDI KARAOKE FILE")),
+            meta(0, MetaMessage::Text("\\Ça".as_bytes())),
+            meta(96, MetaMessage::Text(b" va")),
+            meta(192, MetaMessage::Text(" très".as_bytes())),
+            meta(192, MetaMessage::Text(b" bien")),
+            meta(0, MetaMessage::EndOfTrack),
+        ]);
+        let mut bytes = Vec::new();
+        smf.write_std(&mut bytes).unwrap();
+
+        let score = read_kar(&bytes, "font.otf".into()).unwrap();
+        let bpms = score
+            .bpms
+            .iter()
+            .map(|(beat, bpm)| (beat.0.to_integer(), bpm.0.round()))
+            .collect::<Vec<_>>();
+        assert_eq!(bpms, vec![(0.into(), 120.0), (4.into(), 60.0)]);
+        let (beat, length) = score.measure_lengths.iter().next_back().unwrap();
+        assert_eq!(beat.0.to_integer(), 4.into());
+        assert_eq!(length.fraction(), (&3.into(), &4.into()));
+
+        assert_eq!(score.lyrics, "Ça va très bien");
+        let syllables = score.tracks[0].syllables();
+        assert_eq!(syllables[2].text, " très");
+        // "très" is sung from beat 3 to 5, across the tempo change
+        let (start, end) = &syllables[2].notes[0];
+        assert!((score.beat_to_time(start) - 1.5).abs() < 1e-9);
+        assert!((score.beat_to_time(end) - 3.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,72 @@
+use std::io;
+use std::io::Write;
+
+use itertools::Itertools;
+
+use super::split_time;
+use crate::schema::BeatPosition;
+use crate::schema::Score;
+
+/// Writes each track with lyrics as a line of Enhanced LRC, with a timestamp before every
+/// syllable and one after the last note.
+pub fn write_lrc(score: &Score, mut out: impl Write) -> io::Result<()> {
+    let timestamp = |beat: &BeatPosition| {
+        let (minutes, seconds, centis) = split_time(score.beat_to_time(beat), 100);
+        format!("{:02}:{:02}.{:02}", minutes, seconds, centis)
+    };
+    for track in score
+        .tracks
+        .iter()
+        .sorted_by_key(|track| track.start_beat())
+    {
+        let syllables = track.syllables();
+        let (first_beat, last_beat) = match (
+            syllables.iter().find_map(|s| s.notes.first()),
+            syllables.iter().rev().find_map(|s| s.notes.last()),
+        ) {
+            (Some((first_beat, _)), Some((_, last_beat))) => (first_beat, last_beat),
+            _ => continue,
+        };
+        let mut line = format!("[{}]", timestamp(first_beat));
+        for syllable in &syllables {
+            let text = syllable.text.replace(['\r', '\n'], " ");
+            let word = text.trim_start();
+            line.push_str(&text[..text.len() - word.len()]);
+            if let Some((start_beat, _)) = syllable.notes.first() {
+                line.push_str(&format!("<{}>", timestamp(start_beat)));
+            }
+            line.push_str(word);
+        }
+        line.push_str(&format!("<{}>", timestamp(last_beat)));
+        writeln!(out, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test_util::sample_score;
+    use super::super::test_util::variable_score;
+    use super::write_lrc;
+
+    #[test]
+    fn test_write_lrc() {
+        let mut out = Vec::new();
+        write_lrc(&sample_score(), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[00:00.00]<00:00.00>Hello <00:00.50>big <00:01.50>world<00:02.00>\n"
+        );
+    }
+
+    #[test]
+    fn test_write_lrc_variable_tempo() {
+        // The syllables are sliced at characters, and "très" is held across the tempo change
+        let mut out = Vec::new();
+        write_lrc(&variable_score(), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[00:00.00]<00:00.00>Ça <00:00.50>va <00:01.50>très <00:03.00>bien<00:04.00>\n"
+        );
+    }
+}
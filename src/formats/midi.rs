@@ -0,0 +1,483 @@
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+
+use im::OrdMap;
+use itertools::Itertools;
+use midly::num::u15;
+use midly::num::u24;
+use midly::num::u28;
+use midly::num::u4;
+use midly::num::u7;
+use midly::Arena;
+use midly::Format;
+use midly::Fps;
+use midly::Header;
+use midly::MetaMessage;
+use midly::MidiMessage;
+use midly::Smf;
+use midly::SmpteTime;
+use midly::Timing;
+use midly::TrackEvent;
+use midly::TrackEventKind;
+use num::BigInt;
+use num::BigRational;
+use num::Integer;
+use num::One;
+use num::ToPrimitive;
+use num::Zero;
+use thiserror::Error;
+
+use super::TrackBuilder;
+use crate::schema::BeatLength;
+use crate::schema::BeatPosition;
+use crate::schema::Bpm;
+use crate::schema::MeasureLength;
+use crate::schema::Score;
+use crate::schema::Track;
+
+#[derive(Clone, Debug)]
+pub struct MidiExportOptions {
+    /// Ticks per beat. Every beat in the score must be a whole number of ticks.
+    pub ppq: u16,
+    /// `Score` has no pitches, so every note is written with this key.
+    pub key: u8,
+    pub velocity: u8,
+}
+
+impl Default for MidiExportOptions {
+    fn default() -> Self {
+        Self {
+            ppq: 480,
+            key: 60,
+            velocity: 100,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum MidiError {
+    #[error("{0}")]
+    IOError(#[from] io::Error),
+    #[error("{0}")]
+    ParseError(#[from] midly::Error),
+    #[error("PPQ must be between 1 and 32767, found {0}")]
+    InvalidPpq(u16),
+    #[error("key must be between 0 and 127, found {0}")]
+    InvalidKey(u8),
+    #[error("velocity must be between 0 and 127, found {0}")]
+    InvalidVelocity(u8),
+    #[error("tempo {0} BPM cannot be written to MIDI")]
+    UnsupportedTempo(Bpm),
+    #[error(
+        "offset {0}s cannot be written as an SMPTE offset, which must be between 0 and 24 hours"
+    )]
+    UnsupportedOffset(f64),
+    #[error(
+        "beat {0} is not a whole number of ticks at PPQ {1}; use a PPQ that is a multiple of {2}"
+    )]
+    InexactBeat(BeatPosition, u16, BigInt),
+    #[error("beat {0} is before the start of the file")]
+    NegativeBeat(BeatPosition),
+    #[error("measure length {0} cannot be written as a MIDI time signature")]
+    UnsupportedTimeSignature(MeasureLength),
+    #[error("SMPTE timecode timing is not supported")]
+    TimecodeTiming,
+}
+
+/// Writes the score as a type-1 Standard MIDI File. The first track holds the tempo map and
+/// time signatures, and each `Track` becomes a MIDI track with a Lyric event at the first note
+/// of each syllable. A positive `Score.offset` is written as an SMPTE offset; a negative one is an
+/// error, since the file cannot start before the music.
+pub fn write_midi(
+    score: &Score,
+    options: &MidiExportOptions,
+    out: impl Write,
+) -> Result<(), MidiError> {
+    let ppq = u15::try_from(options.ppq)
+        .filter(|ppq| ppq.as_int() > 0)
+        .ok_or(MidiError::InvalidPpq(options.ppq))?;
+    let key = u7::try_from(options.key).ok_or(MidiError::InvalidKey(options.key))?;
+    let vel = u7::try_from(options.velocity).ok_or(MidiError::InvalidVelocity(options.velocity))?;
+    let to_tick = |beat: &BeatPosition| -> Result<u32, MidiError> {
+        let ticks = &beat.0 * BigInt::from(options.ppq);
+        if !ticks.is_integer() {
+            let denominator = beat.0.denom().clone();
+            return Err(MidiError::InexactBeat(
+                beat.clone(),
+                options.ppq,
+                denominator,
+            ));
+        }
+        ticks
+            .to_integer()
+            .to_u32()
+            .ok_or_else(|| MidiError::NegativeBeat(beat.clone()))
+    };
+
+    let arena = Arena::new();
+    let mut conductor = Vec::new();
+    conductor.push((0, meta(MetaMessage::TrackName(b"Tempo"))));
+    if let Some(smpte) = smpte_offset(score.offset)? {
+        conductor.push((0, meta(MetaMessage::SmpteOffset(smpte))));
+    }
+    // The tempo before the first change is the first tempo, as in `beat_to_time`.
+    let first_bpm = score.bpms.values().next().copied().unwrap_or_default();
+    conductor.push((0, tempo_event(first_bpm)?));
+    for (beat, bpm) in score.bpms.iter().skip_while(|(beat, _)| beat.0.is_zero()) {
+        conductor.push((to_tick(beat)?, tempo_event(*bpm)?));
+    }
+    for (beat, length) in &score.measure_lengths {
+        let (numerator, denominator) = length.fraction();
+        // The denominator is written as a power of two.
+        let power = denominator
+            .trailing_zeros()
+            .filter(|&power| BigInt::one() << power == *denominator)
+            .and_then(|power| power.to_u8());
+        let (numerator, power) = match (numerator.to_u8(), power) {
+            (Some(numerator), Some(power)) if numerator > 0 => (numerator, power),
+            _ => return Err(MidiError::UnsupportedTimeSignature(length.clone())),
+        };
+        let message = MetaMessage::TimeSignature(numerator, power, 24, 8);
+        conductor.push((to_tick(beat)?, meta(message)));
+    }
+    let mut tracks = vec![into_track(conductor)];
+
+    for (i, track) in score
+        .tracks
+        .iter()
+        .sorted_by_key(|track| track.start_beat())
+        .enumerate()
+    {
+        let name = arena.add(format!("Track {}", i + 1).as_bytes());
+        // Events are sorted by tick, then note-offs before lyrics before note-ons.
+        let mut events = vec![(0, 0, meta(MetaMessage::TrackName(name)))];
+        let mut pending_text = String::new();
+        for syllable in track.syllables() {
+            pending_text.push_str(&syllable.text.replace(['\r', '\n'], " "));
+            for (j, (start, end)) in syllable.notes.iter().enumerate() {
+                let (start, end) = (to_tick(start)?, to_tick(end)?);
+                if j == 0 {
+                    let text = arena.add(std::mem::take(&mut pending_text).as_bytes());
+                    events.push((start, 1, meta(MetaMessage::Lyric(text))));
+                }
+                events.push((start, 2, note(MidiMessage::NoteOn { key, vel })));
+                events.push((end, 0, note(MidiMessage::NoteOff { key, vel })));
+            }
+        }
+        events.sort_by_key(|&(tick, order, _)| (tick, order));
+        let events = events.into_iter().map(|(tick, _, kind)| (tick, kind));
+        tracks.push(into_track(events.collect()));
+    }
+
+    let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(ppq)));
+    smf.tracks = tracks;
+    smf.write_std(out)?;
+    Ok(())
+}
+
+fn meta(message: MetaMessage<'_>) -> TrackEventKind<'_> {
+    TrackEventKind::Meta(message)
+}
+
+fn note<'a>(message: MidiMessage) -> TrackEventKind<'a> {
+    TrackEventKind::Midi {
+        channel: u4::new(0),
+        message,
+    }
+}
+
+/// Fails for tempos below about 3.6 BPM, whose microseconds per beat don't fit in 24 bits.
+fn tempo_event<'a>(bpm: Bpm) -> Result<TrackEventKind<'a>, MidiError> {
+    let micros_per_beat = (60_000_000.0 / bpm.0).round();
+    let micros_per_beat = (1.0..=u24::max_value().as_int() as f64)
+        .contains(&micros_per_beat)
+        .then(|| u24::new(micros_per_beat as u32))
+        .ok_or(MidiError::UnsupportedTempo(bpm))?;
+    Ok(meta(MetaMessage::Tempo(micros_per_beat)))
+}
+
+fn smpte_offset(offset: f64) -> Result<Option<SmpteTime>, MidiError> {
+    if offset == 0.0 {
+        return Ok(None);
+    }
+    if offset < 0.0 || offset.is_nan() {
+        return Err(MidiError::UnsupportedOffset(offset));
+    }
+    let centiframes = (offset * 30.0 * 100.0).round() as u64;
+    let (frames, subframe) = centiframes.div_rem(&100);
+    let (seconds, frame) = frames.div_rem(&30);
+    (seconds / 3600)
+        .to_u8()
+        .and_then(|hour| {
+            SmpteTime::new(
+                hour,
+                (seconds / 60 % 60) as u8,
+                (seconds % 60) as u8,
+                frame as u8,
+                subframe as u8,
+                Fps::Fps30,
+            )
+        })
+        .map(Some)
+        .ok_or(MidiError::UnsupportedOffset(offset))
+}
+
+/// Converts events with absolute ticks, in order, into a track ending with End of Track.
+fn into_track(events: Vec<(u32, TrackEventKind<'_>)>) -> Vec<TrackEvent<'_>> {
+    let mut last_tick = 0;
+    events
+        .into_iter()
+        .chain(std::iter::once((0, meta(MetaMessage::EndOfTrack))))
+        .map(|(tick, kind)| {
+            let tick = tick.max(last_tick);
+            let delta = tick - std::mem::replace(&mut last_tick, tick);
+            TrackEvent {
+                delta: u28::new(delta),
+                kind,
+            }
+        })
+        .collect()
+}
+
+/// Reads a Standard MIDI File. Tempo and time signature events of all tracks make up the tempo
+/// map, and each MIDI track with notes becomes a `Track` whose syllables are its Lyric events.
+pub fn read_midi(input: &[u8], font_file: PathBuf) -> Result<Score, MidiError> {
+    let smf = Smf::parse(input)?;
+    let ppq = ppq(&smf)?;
+    let mut score = Score::new(font_file);
+    read_tempo_map(&smf, &mut score)?;
+    for events in &smf.tracks {
+        let lyrics = events_with_ticks(events)
+            .filter_map(|(tick, kind)| match kind {
+                TrackEventKind::Meta(MetaMessage::Lyric(text)) => {
+                    Some((tick, String::from_utf8_lossy(text).into_owned()))
+                }
+                _ => None,
+            })
+            .collect_vec();
+        if let Some(track) = build_track(events, ppq, lyrics) {
+            score.tracks.push_back(track);
+        }
+    }
+    score.lyrics = score
+        .tracks
+        .iter()
+        .filter_map(|track| track.lyrics.as_ref())
+        .map(|lyrics| lyrics.text.trim())
+        .join("\n");
+    Ok(score)
+}
+
+pub(super) fn ppq(smf: &Smf<'_>) -> Result<u16, MidiError> {
+    match smf.header.timing {
+        Timing::Metrical(ppq) if ppq.as_int() > 0 => Ok(ppq.as_int()),
+        Timing::Metrical(ppq) => Err(MidiError::InvalidPpq(ppq.as_int())),
+        Timing::Timecode(..) => Err(MidiError::TimecodeTiming),
+    }
+}
+
+pub(super) fn tick_to_beat(tick: u64, ppq: u16) -> BeatPosition {
+    BeatPosition(BigRational::new(BigInt::from(tick), BigInt::from(ppq)))
+}
+
+/// Yields the events of a track with their absolute ticks.
+pub(super) fn events_with_ticks<'a, 'b>(
+    events: &'b [TrackEvent<'a>],
+) -> impl Iterator<Item = (u64, TrackEventKind<'a>)> + 'b {
+    events.iter().scan(0, |tick, event| {
+        *tick += u64::from(event.delta.as_int());
+        Some((*tick, event.kind))
+    })
+}
+
+/// Fills `score.bpms`, `score.measure_lengths` and `score.offset` from the events of all
+/// tracks. Without a tempo event at the start, MIDI assumes 120 BPM.
+pub(super) fn read_tempo_map(smf: &Smf<'_>, score: &mut Score) -> Result<(), MidiError> {
+    let ppq = ppq(smf)?;
+    let mut bpms = OrdMap::new();
+    let mut measure_lengths = OrdMap::new();
+    for events in &smf.tracks {
+        for (tick, kind) in events_with_ticks(events) {
+            match kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(micros_per_beat))
+                    if micros_per_beat.as_int() > 0 =>
+                {
+                    let bpm = Bpm(60_000_000.0 / f64::from(micros_per_beat.as_int()));
+                    bpms.insert(tick_to_beat(tick, ppq), bpm);
+                }
+                TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, power, _, _))
+                    if numerator > 0 && power < 32 =>
+                {
+                    let length = MeasureLength::new(numerator, BigInt::one() << power);
+                    measure_lengths.insert(tick_to_beat(tick, ppq), length);
+                }
+                TrackEventKind::Meta(MetaMessage::SmpteOffset(smpte)) if tick == 0 => {
+                    score.offset = f64::from(smpte.hour()) * 3600.0
+                        + f64::from(smpte.minute()) * 60.0
+                        + f64::from(smpte.second())
+                        + (f64::from(smpte.frame()) + f64::from(smpte.subframe()) / 100.0)
+                            / f64::from(smpte.fps().as_f32());
+                }
+                _ => {}
+            }
+        }
+    }
+    if !bpms.contains_key(&BeatPosition::zero()) {
+        bpms.insert(BeatPosition::zero(), Bpm::default());
+    }
+    score.bpms = bpms;
+    score.measure_lengths = measure_lengths;
+    Ok(())
+}
+
+/// Builds a track from the notes of a MIDI track, treating it as monophonic.
+/// Each syllable starts at the note at or after its tick; notes without one continue the
+/// previous syllable.
+pub(super) fn build_track(
+    events: &[TrackEvent<'_>],
+    ppq: u16,
+    syllables: Vec<(u64, String)>,
+) -> Option<Track> {
+    let mut notes = Vec::new();
+    let mut open_notes = Vec::new();
+    for (tick, kind) in events_with_ticks(events) {
+        if let TrackEventKind::Midi { channel, message } = kind {
+            match message {
+                MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => {
+                    open_notes.push((channel, key, tick));
+                }
+                MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                    if let Some(i) = open_notes
+                        .iter()
+                        .position(|&(c, k, _)| (c, k) == (channel, key))
+                    {
+                        let (_, _, start) = open_notes.remove(i);
+                        notes.push((start, tick));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    notes.sort_unstable();
+
+    let mut builder = TrackBuilder::new(BeatLength::one());
+    let mut syllables = syllables.into_iter().peekable();
+    for (i, &(start, end)) in notes.iter().enumerate() {
+        let next_start = notes.get(i + 1).map_or(u64::MAX, |&(start, _)| start);
+        let mut text = None;
+        while let Some((_, syllable)) = syllables.next_if(|(tick, _)| *tick < next_start) {
+            text.get_or_insert_with(String::new).push_str(&syllable);
+        }
+        let (start, end) = (tick_to_beat(start, ppq), tick_to_beat(end, ppq));
+        match text {
+            Some(text) => builder.push_syllable(&text, start, end),
+            None => builder.push_note(start, end),
+        }
+    }
+    builder.build()
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test_util::sample_score;
+    use super::super::test_util::variable_score;
+    use super::read_midi;
+    use super::write_midi;
+    use super::MidiError;
+    use super::MidiExportOptions;
+    use crate::schema::BeatPosition;
+    use crate::schema::Bpm;
+    use crate::schema::MeasureLength;
+
+    #[test]
+    fn test_midi_round_trip() {
+        let mut score = sample_score();
+        score.offset = 1.5;
+        score.measure_lengths.insert(
+            num::BigRational::from_integer(8.into()).into(),
+            MeasureLength::new(6, 8),
+        );
+        let mut out = Vec::new();
+        write_midi(&score, &MidiExportOptions::default(), &mut out).unwrap();
+
+        let imported = read_midi(&out, "font.otf".into()).unwrap();
+        assert!((imported.offset - 1.5).abs() < 1e-9);
+        assert_eq!(imported.bpms.len(), 1);
+        assert!((imported.bpms.values().next().unwrap().0 - 120.0).abs() < 1e-9);
+        let (beat, length) = imported.measure_lengths.iter().next().unwrap();
+        assert_eq!(beat.0, num::BigRational::from_integer(8.into()));
+        assert_eq!(length.fraction(), (&6.into(), &8.into()));
+        assert_eq!(imported.tracks.len(), 1);
+        assert_eq!(imported.tracks[0].syllables(), score.tracks[0].syllables());
+    }
+
+    #[test]
+    fn test_midi_tempo_change() {
+        let score = variable_score();
+        let mut out = Vec::new();
+        write_midi(&score, &MidiExportOptions::default(), &mut out).unwrap();
+
+        let imported = read_midi(&out, "font.otf".into()).unwrap();
+        let bpms = imported
+            .bpms
+            .iter()
+            .map(|(beat, bpm)| (beat.clone(), bpm.0.round()))
+            .collect::<Vec<_>>();
+        let change = BeatPosition::from(num::BigRational::from_integer(4.into()));
+        assert_eq!(
+            bpms,
+            vec![(BeatPosition::zero(), 120.0), (change.clone(), 60.0)]
+        );
+        let measure_lengths = imported.measure_lengths.iter().collect::<Vec<_>>();
+        assert_eq!(measure_lengths.len(), 1);
+        assert_eq!(measure_lengths[0].0, &change);
+        assert_eq!(measure_lengths[0].1.fraction(), (&3.into(), &4.into()));
+        // Lyric events hold the multi-byte syllables, and the held note keeps its length
+        assert_eq!(imported.tracks[0].syllables(), score.tracks[0].syllables());
+    }
+
+    #[test]
+    fn test_inexact_ppq() {
+        let options = MidiExportOptions {
+            ppq: 2,
+            ..Default::default()
+        };
+        let mut score = sample_score();
+        score.tracks[0].start_beat = num::BigRational::new(1.into(), 3.into()).into();
+        let result = write_midi(&score, &options, Vec::new());
+        match result {
+            Err(MidiError::InexactBeat(_, 2, multiple)) => assert_eq!(multiple, 3.into()),
+            _ => panic!("expected an inexact beat error"),
+        }
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        let score = sample_score();
+        let options = MidiExportOptions {
+            key: 128,
+            ..Default::default()
+        };
+        let result = write_midi(&score, &options, Vec::new());
+        assert!(matches!(result, Err(MidiError::InvalidKey(128))));
+        let options = MidiExportOptions {
+            velocity: 200,
+            ..Default::default()
+        };
+        let result = write_midi(&score, &options, Vec::new());
+        assert!(matches!(result, Err(MidiError::InvalidVelocity(200))));
+
+        let mut slow = sample_score();
+        slow.bpms
+            .insert(num::BigRational::from_integer(4.into()).into(), Bpm(3.0));
+        let result = write_midi(&slow, &MidiExportOptions::default(), Vec::new());
+        assert!(matches!(result, Err(MidiError::UnsupportedTempo(_))));
+
+        let mut early = sample_score();
+        early.offset = -0.5;
+        let result = write_midi(&early, &MidiExportOptions::default(), Vec::new());
+        assert!(matches!(result, Err(MidiError::UnsupportedOffset(_))));
+    }
+}
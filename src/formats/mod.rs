@@ -0,0 +1,258 @@
+pub mod ass;
+pub mod audacity;
+pub mod json;
+pub mod kar;
+pub mod lrc;
+pub mod midi;
+pub mod musicxml;
+pub mod subtitles;
+pub mod ultrastar;
+
+use im::OrdMap;
+use im::Vector;
+use num::BigInt;
+use num::BigRational;
+use num::ToPrimitive;
+use num::Zero;
+
+use crate::schema::BeatLength;
+use crate::schema::BeatPosition;
+use crate::schema::Lyrics;
+use crate::schema::ScoreElement;
+use crate::schema::ScoreElementKind;
+use crate::schema::Track;
+
+/// Splits a time in seconds into minutes, seconds and the remaining fraction of a second in
+/// units of `1 / scale`, rounded to the nearest unit.
+pub fn split_time(time: f64, scale: u64) -> (u64, u64, u64) {
+    let units = ((time + 0.5 / scale as f64) * scale as f64) as u64;
+    (
+        units / (60 * scale),
+        units % (60 * scale) / scale,
+        units % scale,
+    )
+}
+
+/// Rounds a beat to the nearest multiple of `grid`.
+pub fn quantize(beat: f64, grid: &BeatLength) -> BeatPosition {
+    let steps = (beat / grid.0.to_f64().unwrap()).round() as i64;
+    BeatPosition(BigRational::from_integer(BigInt::from(steps)) * &grid.0)
+}
+
+/// Assembles a track from notes given in order, splitting notes and rests into elements of at
+/// most `step` beats like the ones typed in the editor.
+/// A note starting before the end of the previous one is moved after it, and empty notes are
+/// lengthened to one step.
+/// Syllables are mapped by character indices like `Lyrics::mappings`, whatever the font does
+/// with them.
+pub struct TrackBuilder {
+    step: BeatLength,
+    start_beat: Option<BeatPosition>,
+    end_beat: BeatPosition,
+    elements: Vector<ScoreElement>,
+    text: String,
+    mappings: OrdMap<(usize, usize), usize>,
+    last_mapping: Option<(usize, usize)>,
+}
+
+impl TrackBuilder {
+    pub fn new(step: BeatLength) -> Self {
+        Self {
+            step,
+            start_beat: None,
+            end_beat: BeatPosition::zero(),
+            elements: Vector::new(),
+            text: String::new(),
+            mappings: OrdMap::new(),
+            last_mapping: None,
+        }
+    }
+
+    /// Appends lyrics that are not sung on their own, such as spaces between words.
+    pub fn push_text(&mut self, text: &str) {
+        self.text.push_str(text);
+    }
+
+    /// Appends a syllable sung over a single note. Surrounding whitespace is left unmapped.
+    pub fn push_syllable(&mut self, text: &str, start: BeatPosition, end: BeatPosition) {
+        let word = text.trim();
+        let leading = &text[..text.len() - text.trim_start().len()];
+        let trailing = &text[text.trim_end().len()..];
+        self.push_text(leading);
+        if !word.is_empty() {
+            let start_index = self.text.chars().count();
+            self.text.push_str(word);
+            let mapping = (start_index, self.text.chars().count());
+            self.mappings.insert(mapping, 0);
+            self.last_mapping = Some(mapping);
+        }
+        self.push_note(start, end);
+        self.push_text(trailing);
+    }
+
+    /// Appends a note that continues the previous syllable.
+    pub fn push_note(&mut self, start: BeatPosition, end: BeatPosition) {
+        let start = match &self.start_beat {
+            Some(_) => start.max(self.end_beat.clone()),
+            None => {
+                self.start_beat = Some(start.clone());
+                self.end_beat = start.clone();
+                start
+            }
+        };
+        let end = end.max(&start + &self.step);
+        let rest = &start.0 - &self.end_beat.0;
+        self.push_elements(ScoreElementKind::Stop, ScoreElementKind::Stop, rest);
+        let length = &end.0 - &start.0;
+        self.push_elements(ScoreElementKind::Start, ScoreElementKind::Skip, length);
+        self.end_beat = end;
+        if let Some(count) = self.last_mapping.and_then(|m| self.mappings.get_mut(&m)) {
+            *count += 1;
+        }
+    }
+
+    fn push_elements(
+        &mut self,
+        first_kind: ScoreElementKind,
+        kind: ScoreElementKind,
+        mut length: BigRational,
+    ) {
+        let mut next_kind = first_kind;
+        while length > BigRational::zero() {
+            let element_length = (&length).min(&self.step.0).clone();
+            length -= &element_length;
+            self.elements.push_back(ScoreElement {
+                kind: next_kind,
+                length: BeatLength(element_length),
+            });
+            next_kind = kind;
+        }
+    }
+
+    /// Returns `None` if no notes have been pushed.
+    pub fn build(self) -> Option<Track> {
+        let lyrics = if self.text.trim().is_empty() {
+            None
+        } else {
+            Some(Lyrics {
+                text: self.text,
+                mappings: self.mappings,
+            })
+        };
+        Some(Track {
+            start_beat: self.start_beat?,
+            elements: self.elements,
+            lyrics,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use im::ordmap;
+    use num::BigRational;
+
+    use super::TrackBuilder;
+    use crate::schema::BeatLength;
+    use crate::schema::BeatPosition;
+
+    fn beat(x: i64) -> BeatPosition {
+        BeatPosition::from(BigRational::from_integer(x.into()))
+    }
+
+    #[test]
+    fn test_mappings_in_chars() {
+        let mut builder = TrackBuilder::new(BeatLength::one());
+        // "fi" forms a ligature in many fonts, and "é" takes two bytes
+        builder.push_syllable("of", beat(0), beat(1));
+        builder.push_syllable("fice ", beat(1), beat(2));
+        builder.push_syllable("café", beat(2), beat(3));
+        let track = builder.build().unwrap();
+        let lyrics = track.lyrics.as_ref().unwrap();
+        assert_eq!(
+            lyrics.mappings,
+            ordmap![(0, 2) => 1, (2, 6) => 1, (7, 11) => 1]
+        );
+        let texts = track.syllables().iter().map(|s| s.text).collect::<Vec<_>>();
+        assert_eq!(texts, vec!["of", "fice", " café"]);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_util {
+    use im::ordmap;
+    use im::Vector;
+    use num::BigRational;
+
+    use crate::schema::BeatLength;
+    use crate::schema::BeatPosition;
+    use crate::schema::Bpm;
+    use crate::schema::Lyrics;
+    use crate::schema::MeasureLength;
+    use crate::schema::Score;
+    use crate::schema::ScoreElement;
+    use crate::schema::ScoreElementKind;
+    use crate::schema::Track;
+
+    fn beat(x: i64) -> BeatPosition {
+        BeatPosition::from(BigRational::from_integer(x.into()))
+    }
+
+    /// Elements of a beat each
+    fn elements(kinds: &[ScoreElementKind]) -> Vector<ScoreElement> {
+        kinds
+            .iter()
+            .map(|&kind| ScoreElement {
+                kind,
+                length: BeatLength::from(BigRational::from_integer(1.into())),
+            })
+            .collect()
+    }
+
+    /// A score at 120 BPM with one track singing "Hello big world" on beats 0, 1 and 3.
+    pub fn sample_score() -> Score {
+        let mut score = Score::new("font.otf".into());
+        score.bpms.insert(BeatPosition::zero(), Bpm(120.0));
+        score.tracks.push_back(Track {
+            start_beat: BeatPosition::zero(),
+            elements: elements(&[
+                ScoreElementKind::Start,
+                ScoreElementKind::Start,
+                ScoreElementKind::Stop,
+                ScoreElementKind::Start,
+            ]),
+            lyrics: Some(Lyrics {
+                text: "Hello big world".to_owned(),
+                mappings: ordmap![(0, 5) => 1, (6, 9) => 1, (10, 15) => 1],
+            }),
+        });
+        score
+    }
+
+    /// A score that slows from 120 to 60 BPM and changes from 4/4 to 3/4 at beat 4, with one
+    /// track singing "Ça va très bien" on beats 0, 1, 3 to 5 across the change, and 5.
+    pub fn variable_score() -> Score {
+        let mut score = Score::new("font.otf".into());
+        score.bpms.insert(BeatPosition::zero(), Bpm(120.0));
+        score.bpms.insert(beat(4), Bpm(60.0));
+        score
+            .measure_lengths
+            .insert(beat(4), MeasureLength::new(3, 4));
+        score.tracks.push_back(Track {
+            start_beat: BeatPosition::zero(),
+            elements: elements(&[
+                ScoreElementKind::Start,
+                ScoreElementKind::Start,
+                ScoreElementKind::Stop,
+                ScoreElementKind::Start,
+                ScoreElementKind::Skip,
+                ScoreElementKind::Start,
+            ]),
+            lyrics: Some(Lyrics {
+                text: "Ça va très bien".to_owned(),
+                mappings: ordmap![(0, 2) => 1, (3, 5) => 1, (6, 10) => 1, (11, 15) => 1],
+            }),
+        });
+        score
+    }
+}
@@ -0,0 +1,602 @@
+use std::io;
+use std::io::Write;
+use std::iter;
+
+use itertools::Itertools;
+use num::BigInt;
+use num::BigRational;
+use num::Integer;
+use num::One;
+use num::Signed;
+use num::Zero;
+
+use crate::schema::iterate_measures;
+use crate::schema::BeatPosition;
+use crate::schema::Bpm;
+use crate::schema::MeasureLength;
+use crate::schema::Score;
+
+/// Note types from a whole note down, with their lengths in beats as powers of two.
+const NOTE_TYPES: [(&str, i32); 11] = [
+    ("whole", 2),
+    ("half", 1),
+    ("quarter", 0),
+    ("eighth", -1),
+    ("16th", -2),
+    ("32nd", -3),
+    ("64th", -4),
+    ("128th", -5),
+    ("256th", -6),
+    ("512th", -7),
+    ("1024th", -8),
+];
+
+/// A note of the vocal line, in beats.
+struct VocalNote {
+    start: BigRational,
+    end: BigRational,
+    lyric: Option<(String, &'static str)>,
+}
+
+struct Measure {
+    time_signature: Option<MeasureLength>,
+    items: Vec<MeasureItem>,
+}
+
+enum MeasureItem {
+    Tempo(f64),
+    Note {
+        /// Actual length in beats
+        length: BigRational,
+        note_type: &'static str,
+        dots: u8,
+        /// Actual and normal notes of a tuplet
+        tuplet: Option<(BigInt, BigInt)>,
+        /// Whether the note starts or stops a tuplet bracket
+        tuplet_start: bool,
+        tuplet_stop: bool,
+        rest: bool,
+        tie_start: bool,
+        tie_stop: bool,
+        lyric: Option<(String, &'static str)>,
+    },
+}
+
+/// Writes all tracks as a single vocal line in MusicXML. Notes are split at bar lines and tempo
+/// changes and spelled as tied, dotted and tuplet values of their exact lengths.
+/// `Score` has no pitches, so every note sits on the middle line of a treble staff.
+/// Overlapping notes are cut at the start of the next one, and notes before beat 0 are clipped.
+pub fn write_musicxml(score: &Score, title: &str, mut out: impl Write) -> io::Result<()> {
+    let notes = vocal_line(score);
+    // The tempo at beat 0 is written even without a change there, as in `beat_to_time`.
+    let first_bpm = score
+        .bpms
+        .range(..=BeatPosition::zero())
+        .next_back()
+        .or_else(|| score.bpms.iter().next())
+        .map_or_else(Bpm::default, |(_, bpm)| *bpm);
+    let tempos = iter::once((BigRational::zero(), first_bpm.0))
+        .chain(
+            score
+                .bpms
+                .iter()
+                .filter(|(beat, _)| beat.0.is_positive())
+                .map(|(beat, bpm)| (beat.0.clone(), bpm.0)),
+        )
+        .collect_vec();
+    let end = notes
+        .last()
+        .map_or_else(BigRational::zero, |note| note.end.clone());
+
+    let mut measures = Vec::new();
+    let mut last_length = None;
+    for (measure_start, measure_end) in iterate_measures(score.measure_lengths.iter()) {
+        if !measures.is_empty() && measure_start.0 >= end {
+            break;
+        }
+        let length = score
+            .measure_lengths
+            .range(..=&measure_start)
+            .next_back()
+            .map_or_else(MeasureLength::four, |(_, length)| length.clone());
+        let fraction = (length.fraction().0.clone(), length.fraction().1.clone());
+        let time_signature = if last_length.as_ref() != Some(&fraction) {
+            last_length = Some(fraction);
+            Some(length)
+        } else {
+            None
+        };
+        measures.push(Measure {
+            time_signature,
+            items: measure_items(&tempos, &notes, &measure_start.0, &measure_end.0),
+        });
+    }
+
+    let divisions = measures
+        .iter()
+        .flat_map(|measure| &measure.items)
+        .filter_map(|item| match item {
+            MeasureItem::Note { length, .. } => Some(length.denom().clone()),
+            MeasureItem::Tempo(_) => None,
+        })
+        .fold(BigInt::one(), |x, y| x.lcm(&y));
+
+    writeln!(
+        out,
+        r#"<?xml version="1.0" encoding="UTF-8" standalone="no"?>"#
+    )?;
+    writeln!(
+        out,
+        r#"<!DOCTYPE score-partwise PUBLIC "-//Recordare//DTD MusicXML 3.1 Partwise//EN" "http://www.musicxml.org/dtds/partwise.dtd">"#
+    )?;
+    writeln!(out, r#"<score-partwise version="3.1">"#)?;
+    writeln!(
+        out,
+        "  <work><work-title>{}</work-title></work>",
+        escape(title)
+    )?;
+    writeln!(out, "  <part-list>")?;
+    writeln!(
+        out,
+        r#"    <score-part id="P1"><part-name>Voice</part-name></score-part>"#
+    )?;
+    writeln!(out, "  </part-list>")?;
+    writeln!(out, r#"  <part id="P1">"#)?;
+    for (i, measure) in measures.iter().enumerate() {
+        writeln!(out, r#"    <measure number="{}">"#, i + 1)?;
+        if i == 0 || measure.time_signature.is_some() {
+            writeln!(out, "      <attributes>")?;
+            if i == 0 {
+                writeln!(out, "        <divisions>{}</divisions>", divisions)?;
+            }
+            if let Some(length) = &measure.time_signature {
+                let (beats, beat_type) = length.fraction();
+                writeln!(
+                    out,
+                    "        <time><beats>{}</beats><beat-type>{}</beat-type></time>",
+                    beats, beat_type
+                )?;
+            }
+            if i == 0 {
+                writeln!(out, "        <clef><sign>G</sign><line>2</line></clef>")?;
+            }
+            writeln!(out, "      </attributes>")?;
+        }
+        for item in &measure.items {
+            write_item(&mut out, item, &divisions)?;
+        }
+        writeln!(out, "    </measure>")?;
+    }
+    writeln!(out, "  </part>")?;
+    writeln!(out, "</score-partwise>")?;
+    Ok(())
+}
+
+fn write_item(out: &mut impl Write, item: &MeasureItem, divisions: &BigInt) -> io::Result<()> {
+    match item {
+        MeasureItem::Tempo(bpm) => {
+            writeln!(out, r#"      <direction placement="above">"#)?;
+            writeln!(
+                out,
+                "        <direction-type><metronome><beat-unit>quarter</beat-unit>\
+                 <per-minute>{}</per-minute></metronome></direction-type>",
+                bpm
+            )?;
+            writeln!(out, r#"        <sound tempo="{}"/>"#, bpm)?;
+            writeln!(out, "      </direction>")?;
+        }
+        MeasureItem::Note {
+            length,
+            note_type,
+            dots,
+            tuplet,
+            tuplet_start,
+            tuplet_stop,
+            rest,
+            tie_start,
+            tie_stop,
+            lyric,
+        } => {
+            writeln!(out, "      <note>")?;
+            if *rest {
+                writeln!(out, "        <rest/>")?;
+            } else {
+                writeln!(
+                    out,
+                    "        <pitch><step>B</step><octave>4</octave></pitch>"
+                )?;
+            }
+            let duration = (length * divisions).to_integer();
+            writeln!(out, "        <duration>{}</duration>", duration)?;
+            if *tie_stop {
+                writeln!(out, r#"        <tie type="stop"/>"#)?;
+            }
+            if *tie_start {
+                writeln!(out, r#"        <tie type="start"/>"#)?;
+            }
+            writeln!(out, "        <type>{}</type>", note_type)?;
+            for _ in 0..*dots {
+                writeln!(out, "        <dot/>")?;
+            }
+            if let Some((actual, normal)) = tuplet {
+                writeln!(
+                    out,
+                    "        <time-modification><actual-notes>{}</actual-notes>\
+                     <normal-notes>{}</normal-notes></time-modification>",
+                    actual, normal
+                )?;
+            }
+            if *tie_start || *tie_stop || *tuplet_start || *tuplet_stop {
+                writeln!(out, "        <notations>")?;
+                if *tie_stop {
+                    writeln!(out, r#"          <tied type="stop"/>"#)?;
+                }
+                if *tie_start {
+                    writeln!(out, r#"          <tied type="start"/>"#)?;
+                }
+                if *tuplet_start {
+                    writeln!(out, r#"          <tuplet type="start"/>"#)?;
+                }
+                if *tuplet_stop {
+                    writeln!(out, r#"          <tuplet type="stop"/>"#)?;
+                }
+                writeln!(out, "        </notations>")?;
+            }
+            if let Some((text, syllabic)) = lyric {
+                writeln!(
+                    out,
+                    r#"        <lyric number="1"><syllabic>{}</syllabic><text>{}</text></lyric>"#,
+                    syllabic,
+                    escape(text)
+                )?;
+            }
+            writeln!(out, "      </note>")?;
+        }
+    }
+    Ok(())
+}
+
+/// Collects the notes of all tracks in order, with each syllable on its first note.
+fn vocal_line(score: &Score) -> Vec<VocalNote> {
+    let mut notes = Vec::new();
+    for track in score
+        .tracks
+        .iter()
+        .sorted_by_key(|track| track.start_beat())
+    {
+        let syllables = track.syllables();
+        for (i, syllable) in syllables.iter().enumerate() {
+            let text = syllable.text.trim();
+            let starts_word = i == 0
+                || syllable.text.starts_with(char::is_whitespace)
+                || syllables[i - 1].text.ends_with(char::is_whitespace);
+            let ends_word = i + 1 == syllables.len()
+                || syllable.text.ends_with(char::is_whitespace)
+                || syllables[i + 1].text.starts_with(char::is_whitespace);
+            let syllabic = match (starts_word, ends_word) {
+                (true, true) => "single",
+                (true, false) => "begin",
+                (false, false) => "middle",
+                (false, true) => "end",
+            };
+            for (j, (start, end)) in syllable.notes.iter().enumerate() {
+                let lyric = if j == 0 && !text.is_empty() {
+                    Some((text.to_owned(), syllabic))
+                } else {
+                    None
+                };
+                notes.push(VocalNote {
+                    start: start.0.clone(),
+                    end: end.0.clone(),
+                    lyric,
+                });
+            }
+        }
+    }
+    notes.sort_by(|x, y| x.start.cmp(&y.start));
+    for i in 1..notes.len() {
+        let next_start = notes[i].start.clone();
+        let previous = &mut notes[i - 1];
+        if previous.end > next_start {
+            previous.end = next_start;
+        }
+    }
+    for note in &mut notes {
+        if note.start.is_negative() {
+            note.start = BigRational::zero();
+        }
+    }
+    notes.retain(|note| note.start < note.end);
+    notes
+}
+
+/// Fills a measure with notes and rests, split at note boundaries and `tempos` changes.
+fn measure_items(
+    tempos: &[(BigRational, f64)],
+    notes: &[VocalNote],
+    measure_start: &BigRational,
+    measure_end: &BigRational,
+) -> Vec<MeasureItem> {
+    let inside = |beat: &BigRational| measure_start < beat && beat < measure_end;
+    let tempo_changes = tempos
+        .iter()
+        .filter(|(beat, _)| measure_start <= beat && beat < measure_end)
+        .cloned()
+        .collect_vec();
+    let cuts = notes
+        .iter()
+        .flat_map(|note| vec![note.start.clone(), note.end.clone()])
+        .filter(|beat| inside(beat))
+        .chain(tempo_changes.iter().map(|(beat, _)| beat.clone()))
+        .chain(vec![measure_start.clone(), measure_end.clone()])
+        .sorted()
+        .dedup()
+        .collect_vec();
+
+    let mut items = Vec::new();
+    for (start, end) in cuts.iter().tuple_windows() {
+        if let Some((_, bpm)) = tempo_changes.iter().find(|(beat, _)| beat == start) {
+            items.push(MeasureItem::Tempo(*bpm));
+        }
+        let note = notes
+            .iter()
+            .find(|note| &note.start <= start && start < &note.end);
+        let values = note_values(&(end - start));
+        let count = values.len();
+        for (i, (length, note_type, dots, tuplet)) in values.into_iter().enumerate() {
+            let first = i == 0;
+            let last = i + 1 == count;
+            items.push(match note {
+                Some(note) => MeasureItem::Note {
+                    length,
+                    note_type,
+                    dots,
+                    tuplet,
+                    tuplet_start: false,
+                    tuplet_stop: false,
+                    rest: false,
+                    tie_start: !last || end < &note.end,
+                    tie_stop: !first || start > &note.start,
+                    lyric: note.lyric.clone().filter(|_| first && start == &note.start),
+                },
+                None => MeasureItem::Note {
+                    length,
+                    note_type,
+                    dots,
+                    tuplet,
+                    tuplet_start: false,
+                    tuplet_stop: false,
+                    rest: true,
+                    tie_start: false,
+                    tie_stop: false,
+                    lyric: None,
+                },
+            });
+        }
+    }
+    bracket_tuplets(&mut items);
+    items
+}
+
+/// Groups consecutive notes of the same tuplet into brackets, each closed once it fills a
+/// whole number of beats.
+fn bracket_tuplets(items: &mut [MeasureItem]) {
+    let mut brackets = Vec::new();
+    let mut bracket: Option<(usize, usize, (BigInt, BigInt), BigRational)> = None;
+    for (i, item) in items.iter().enumerate() {
+        let (length, tuplet) = match item {
+            MeasureItem::Note { length, tuplet, .. } => (length, tuplet),
+            MeasureItem::Tempo(_) => continue,
+        };
+        if let Some((start, end, ratio, _)) = &bracket {
+            if Some(ratio) != tuplet.as_ref() {
+                brackets.push((*start, *end));
+                bracket = None;
+            }
+        }
+        if let Some(ratio) = tuplet {
+            let (start, total) = match bracket.take() {
+                Some((start, _, _, total)) => (start, total),
+                None => (i, BigRational::zero()),
+            };
+            let total = total + length;
+            if total.is_integer() {
+                brackets.push((start, i));
+            } else {
+                bracket = Some((start, i, ratio.clone(), total));
+            }
+        }
+    }
+    brackets.extend(bracket.map(|(start, end, _, _)| (start, end)));
+    for (start, end) in brackets {
+        if let MeasureItem::Note { tuplet_start, .. } = &mut items[start] {
+            *tuplet_start = true;
+        }
+        if let MeasureItem::Note { tuplet_stop, .. } = &mut items[end] {
+            *tuplet_stop = true;
+        }
+    }
+}
+
+/// Actual length, type, dots and tuplet of a written note
+type NoteValue = (BigRational, &'static str, u8, Option<(BigInt, BigInt)>);
+
+/// Spells a length as note values.
+/// Lengths whose denominator has an odd factor `m` become `m`-tuplets in the time of the
+/// largest power of two below `m`.
+fn note_values(length: &BigRational) -> Vec<NoteValue> {
+    let two = BigInt::from(2);
+    let mut odd = length.denom().clone();
+    while odd.is_even() {
+        odd /= &two;
+    }
+    let (scale, tuplet) = if odd.is_one() {
+        (BigRational::one(), None)
+    } else {
+        let mut normal = BigInt::one();
+        while &normal * &two < odd {
+            normal *= &two;
+        }
+        (
+            BigRational::new(odd.clone(), normal.clone()),
+            Some((odd, normal)),
+        )
+    };
+
+    // Spell the length as written, then scale back to the actual length.
+    let mut remaining = length * &scale;
+    let mut values: Vec<(BigRational, &'static str, u8)> = Vec::new();
+    while remaining.is_positive() {
+        let found = NOTE_TYPES.iter().find_map(|&(note_type, exponent)| {
+            let value = power_of_two(exponent);
+            if value <= remaining {
+                Some((value, note_type))
+            } else {
+                None
+            }
+        });
+        let (value, note_type) = match found {
+            Some(x) => x,
+            None => {
+                // Shorter than any note type; lengthen the previous value instead.
+                match values.last_mut() {
+                    Some(last) => last.0 += &remaining,
+                    None => values.push((remaining.clone(), "1024th", 0)),
+                }
+                break;
+            }
+        };
+        let mut written = value.clone();
+        let mut dot = &value / BigInt::from(2);
+        let mut dots = 0;
+        while dots < 2 && &written + &dot <= remaining {
+            written += &dot;
+            dot /= BigInt::from(2);
+            dots += 1;
+        }
+        remaining -= &written;
+        values.push((written, note_type, dots));
+    }
+    values
+        .into_iter()
+        .map(|(written, note_type, dots)| (written / &scale, note_type, dots, tuplet.clone()))
+        .collect()
+}
+
+fn power_of_two(exponent: i32) -> BigRational {
+    let value = BigInt::one() << exponent.unsigned_abs();
+    if exponent >= 0 {
+        BigRational::from_integer(value)
+    } else {
+        BigRational::new(BigInt::one(), value)
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod test {
+    use im::ordmap;
+    use num::BigRational;
+
+    use super::super::test_util::sample_score;
+    use super::super::test_util::variable_score;
+    use super::note_values;
+    use super::write_musicxml;
+    use crate::schema::BeatPosition;
+    use crate::schema::Bpm;
+    use crate::schema::Lyrics;
+
+    fn rational(numer: i32, denom: i32) -> BigRational {
+        BigRational::new(numer.into(), denom.into())
+    }
+
+    #[test]
+    fn test_note_values() {
+        let values = note_values(&rational(3, 2));
+        assert_eq!(values.len(), 1);
+        assert_eq!((values[0].1, values[0].2), ("quarter", 1));
+
+        let values = note_values(&rational(5, 1));
+        let spelled = values.iter().map(|v| (v.1, v.2)).collect::<Vec<_>>();
+        assert_eq!(spelled, vec![("whole", 0), ("quarter", 0)]);
+
+        let values = note_values(&rational(1, 3));
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0].0, rational(1, 3));
+        assert_eq!(values[0].1, "eighth");
+        assert_eq!(values[0].3, Some((3.into(), 2.into())));
+    }
+
+    #[test]
+    fn test_write_musicxml() {
+        let mut score = sample_score();
+        score.tracks[0].start_beat = BeatPosition::from(rational(3, 1));
+        score.tracks[0].lyrics = Some(Lyrics {
+            text: "Hello big world".to_owned(),
+            mappings: ordmap![(0, 3) => 1, (3, 5) => 1, (6, 15) => 1],
+        });
+        let mut out = Vec::new();
+        write_musicxml(&score, "Song", &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        assert_eq!(out.matches("<measure ").count(), 2);
+        assert!(out.contains("<divisions>1</divisions>"));
+        assert!(out.contains("<time><beats>4</beats><beat-type>4</beat-type></time>"));
+        assert!(out.contains(r#"<sound tempo="120"/>"#));
+        assert!(out.contains("<syllabic>begin</syllabic><text>Hel</text>"));
+        assert!(out.contains("<syllabic>end</syllabic><text>lo</text>"));
+        assert!(out.contains("<syllabic>single</syllabic><text>big world</text>"));
+        assert_eq!(out.matches("<rest/>").count(), 3);
+        assert_eq!(out.matches(r#"<tie type="start"/>"#).count(), 0);
+    }
+
+    #[test]
+    fn test_tuplets_and_clipping() {
+        let mut score = sample_score();
+        score.bpms = ordmap![BeatPosition::from(rational(2, 1)) => Bpm(90.0)];
+        // Notes on [-1/3, 2/3), [2/3, 5/3) and [8/3, 11/3)
+        score.tracks[0].start_beat = BeatPosition::from(rational(-1, 3));
+        let mut out = Vec::new();
+        write_musicxml(&score, "Song", &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        // The tempo before the first change is written at the start
+        assert_eq!(out.matches(r#"<sound tempo="90"/>"#).count(), 2);
+        // The first note is clipped at beat 0, not dropped
+        assert!(out.contains("<text>Hello</text>"));
+        assert!(out.contains("<divisions>3</divisions>"));
+        // The note on [0, 2/3) and the rest on [11/3, 4) are brackets of their own, and the rests
+        // on [5/3, 2) and [2, 8/3), split at the tempo change, share one
+        assert_eq!(out.matches("<time-modification>").count(), 4);
+        assert_eq!(out.matches(r#"<tuplet type="start"/>"#).count(), 3);
+        assert_eq!(out.matches(r#"<tuplet type="stop"/>"#).count(), 3);
+    }
+
+    #[test]
+    fn test_musicxml_variable_score() {
+        let mut out = Vec::new();
+        write_musicxml(&variable_score(), "Song", &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+
+        // The second measure starts with the new time signature and tempo
+        let (first, second) = out.split_at(out.find(r#"<measure number="2">"#).unwrap());
+        assert!(first.contains("<time><beats>4</beats><beat-type>4</beat-type></time>"));
+        assert!(first.contains(r#"<sound tempo="120"/>"#));
+        assert!(second.contains("<time><beats>3</beats><beat-type>4</beat-type></time>"));
+        assert!(second.contains(r#"<sound tempo="60"/>"#));
+        // "très" is tied across the bar line, with its syllable on the first note only
+        assert!(first.contains(r#"<tie type="start"/>"#));
+        assert!(second.contains(r#"<tie type="stop"/>"#));
+        assert_eq!(out.matches("<text>très</text>").count(), 1);
+        assert!(out.contains("<syllabic>single</syllabic><text>Ça</text>"));
+        // The rest on beat 2, and the one filling the last measure
+        assert_eq!(first.matches("<rest/>").count(), 1);
+        assert_eq!(second.matches("<rest/>").count(), 1);
+    }
+}
@@ -0,0 +1,168 @@
+use std::io;
+use std::io::Write;
+
+use itertools::Itertools;
+use serde::Deserialize;
+
+use super::split_time;
+use crate::schema::Score;
+use crate::schema::Track;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct SubtitleOptions {
+    /// Seconds a cue is shown before its track starts.
+    pub lead_in: f64,
+    /// Seconds a cue is shown after its track ends.
+    pub lead_out: f64,
+}
+
+impl Default for SubtitleOptions {
+    fn default() -> Self {
+        Self {
+            lead_in: 1.0,
+            lead_out: 0.5,
+        }
+    }
+}
+
+/// Writes a SubRip cue with the lyrics of each track.
+pub fn write_srt(score: &Score, options: &SubtitleOptions, mut out: impl Write) -> io::Result<()> {
+    for (i, (track, start, end)) in cues(score, options).into_iter().enumerate() {
+        let text = track
+            .lyrics
+            .as_ref()
+            .map_or("", |lyrics| lyrics.text.trim());
+        writeln!(out, "{}", i + 1)?;
+        writeln!(
+            out,
+            "{} --> {}",
+            format_timestamp(start, ','),
+            format_timestamp(end, ',')
+        )?;
+        writeln!(out, "{}", text)?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Writes a WebVTT cue for each track, with a timestamp tag where each syllable starts.
+pub fn write_vtt(score: &Score, options: &SubtitleOptions, mut out: impl Write) -> io::Result<()> {
+    writeln!(out, "WEBVTT")?;
+    writeln!(out)?;
+    for (track, start, end) in cues(score, options) {
+        writeln!(
+            out,
+            "{} --> {}",
+            format_timestamp(start, '.'),
+            format_timestamp(end, '.')
+        )?;
+        let mut text = String::new();
+        for syllable in track.syllables() {
+            let escaped = escape(syllable.text);
+            let word = escaped.trim_start();
+            text.push_str(&escaped[..escaped.len() - word.len()]);
+            if let Some((beat, _)) = syllable.notes.first() {
+                // Timestamps must lie strictly inside the cue.
+                let time = score.beat_to_time(beat);
+                if start < time && time < end {
+                    text.push_str(&format!("<{}>", format_timestamp(time, '.')));
+                }
+            }
+            text.push_str(word);
+        }
+        writeln!(out, "{}", text.trim())?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Tracks with lyrics in order, with the start and end times of their cues.
+fn cues<'a>(score: &'a Score, options: &SubtitleOptions) -> Vec<(&'a Track, f64, f64)> {
+    score
+        .tracks
+        .iter()
+        .filter(|track| track.lyrics.is_some())
+        .sorted_by_key(|track| track.start_beat())
+        .map(|track| {
+            let start = score.beat_to_time(track.start_beat()) - options.lead_in;
+            let end = score.beat_to_time(&track.end_beat()) + options.lead_out;
+            (track, start.max(0.0), end.max(0.0))
+        })
+        .collect()
+}
+
+fn format_timestamp(time: f64, decimal_separator: char) -> String {
+    let (minutes, seconds, millis) = split_time(time, 1000);
+    format!(
+        "{:02}:{:02}:{:02}{}{:03}",
+        minutes / 60,
+        minutes % 60,
+        seconds,
+        decimal_separator,
+        millis
+    )
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test_util::sample_score;
+    use super::super::test_util::variable_score;
+    use super::write_srt;
+    use super::write_vtt;
+    use super::SubtitleOptions;
+
+    #[test]
+    fn test_write_subtitles() {
+        let mut score = sample_score();
+        score.offset = 2.0;
+        let options = SubtitleOptions {
+            lead_in: 1.0,
+            lead_out: 0.25,
+        };
+
+        let mut out = Vec::new();
+        write_srt(&score, &options, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "1\n00:00:01,000 --> 00:00:04,250\nHello big world\n\n"
+        );
+
+        let mut out = Vec::new();
+        write_vtt(&score, &options, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "WEBVTT\n\n00:00:01.000 --> 00:00:04.250\n\
+             <00:00:02.000>Hello <00:00:02.500>big <00:00:03.500>world\n\n"
+        );
+    }
+
+    #[test]
+    fn test_write_subtitles_variable_tempo() {
+        let mut score = variable_score();
+        score.offset = 2.0;
+        let options = SubtitleOptions::default();
+
+        let mut out = Vec::new();
+        write_srt(&score, &options, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "1\n00:00:01,000 --> 00:00:06,500\nÇa va très bien\n\n"
+        );
+
+        // The timestamps skip the rest before "très", and slow down after beat 4
+        let mut out = Vec::new();
+        write_vtt(&score, &options, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "WEBVTT\n\n00:00:01.000 --> 00:00:06.500\n\
+             <00:00:02.000>Ça <00:00:02.500>va <00:00:03.500>très <00:00:05.000>bien\n\n"
+        );
+    }
+}
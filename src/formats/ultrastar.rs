@@ -0,0 +1,319 @@
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+
+use itertools::Itertools;
+use num::BigInt;
+use num::BigRational;
+use num::Integer;
+use num::One;
+use num::ToPrimitive;
+use num::Zero;
+use thiserror::Error;
+
+use super::TrackBuilder;
+use crate::schema::BeatLength;
+use crate::schema::BeatPosition;
+use crate::schema::Bpm;
+use crate::schema::Score;
+use crate::schema::Track;
+
+/// Header fields that have no counterpart in `Score`.
+#[derive(Clone, Debug, Default)]
+pub struct UltraStarMetadata {
+    pub title: String,
+    pub artist: String,
+    /// `#MP3`, relative to the TXT file. Defaults to the file name of the music on export.
+    pub audio: Option<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum UltraStarError {
+    #[error("{0}")]
+    IOError(#[from] io::Error),
+    #[error(
+        "UltraStar files have a single tempo, but the tempo changes at beat {0}; \
+         remove the tempo changes before exporting"
+    )]
+    VariableTempo(BeatPosition),
+    #[error("more than two tracks are sung at the same time at beat {0}")]
+    TooManySingers(BeatPosition),
+    #[error("missing #BPM header")]
+    MissingBpm,
+    #[error("line {0}: {1}")]
+    InvalidLine(usize, String),
+}
+
+/// Writes the score as an UltraStar song. Each track becomes a phrase, and tracks overlapping
+/// in time are split between the two singers of a duet.
+/// Beats are subdivided finely enough to place every note exactly, which requires the score to
+/// have a single tempo.
+pub fn write_ultrastar(
+    score: &Score,
+    metadata: &UltraStarMetadata,
+    mut out: impl Write,
+) -> Result<(), UltraStarError> {
+    let bpm = single_bpm(score)?;
+    let tracks = score
+        .tracks
+        .iter()
+        .filter(|track| track.iterate_notes().next().is_some())
+        .sorted_by_key(|track| track.start_beat())
+        .collect_vec();
+
+    // Units per beat, chosen so that every note boundary falls on a unit
+    let resolution = tracks
+        .iter()
+        .flat_map(|track| track.iterate_notes())
+        .flat_map(|(start, end, _)| vec![start.0.denom().clone(), end.0.denom().clone()])
+        .fold(BigInt::from(4), |x, y| x.lcm(&y));
+    let origin = tracks
+        .iter()
+        .map(|track| track.start_beat().0.floor())
+        .min()
+        .map_or_else(BigRational::zero, |beat| beat.min(BigRational::zero()));
+    let to_units = |beat: &BeatPosition| ((&beat.0 - &origin) * &resolution).to_integer();
+
+    let mut singers: Vec<(BeatPosition, Vec<&Track>)> = Vec::new();
+    for track in tracks {
+        let singer = singers
+            .iter()
+            .position(|(end_beat, _)| end_beat <= track.start_beat());
+        match singer {
+            Some(singer) => {
+                singers[singer].0 = track.end_beat();
+                singers[singer].1.push(track);
+            }
+            None if singers.len() < 2 => singers.push((track.end_beat(), vec![track])),
+            None => return Err(UltraStarError::TooManySingers(track.start_beat().clone())),
+        }
+    }
+
+    writeln!(out, "#TITLE:{}", metadata.title)?;
+    writeln!(out, "#ARTIST:{}", metadata.artist)?;
+    let audio = metadata.audio.clone().or_else(|| {
+        let music = score.music.as_ref()?;
+        Some(music.path.file_name()?.to_string_lossy().into_owned())
+    });
+    if let Some(audio) = audio {
+        writeln!(out, "#MP3:{}", audio)?;
+    }
+    // A unit lasts a quarter of a beat of #BPM.
+    writeln!(out, "#BPM:{}", bpm * resolution.to_f64().unwrap() / 4.0)?;
+    let gap = score.beat_to_time(&BeatPosition(origin.clone())) * 1000.0;
+    writeln!(out, "#GAP:{}", gap.round())?;
+    let duet = singers.len() > 1;
+    for (singer, (_, tracks)) in singers.iter().enumerate() {
+        if duet {
+            writeln!(out, "P{}", singer + 1)?;
+        }
+        for (i, track) in tracks.iter().enumerate() {
+            if i > 0 {
+                let (_, previous_end, _) = tracks[i - 1].iterate_notes().last().unwrap();
+                writeln!(out, "- {}", to_units(&previous_end))?;
+            }
+            let mut pending_text = String::new();
+            for syllable in track.syllables() {
+                pending_text.push_str(&syllable.text.replace(['\r', '\n'], " "));
+                for (start, end) in &syllable.notes {
+                    let text = std::mem::replace(&mut pending_text, "~".to_owned());
+                    let start_unit = to_units(start);
+                    let length = to_units(end) - &start_unit;
+                    writeln!(out, ": {} {} 0 {}", start_unit, length, text)?;
+                }
+                if !syllable.notes.is_empty() {
+                    pending_text.clear();
+                }
+            }
+        }
+    }
+    writeln!(out, "E")?;
+    Ok(())
+}
+
+fn single_bpm(score: &Score) -> Result<f64, UltraStarError> {
+    let mut bpms = score.bpms.iter();
+    let first = bpms.next().map_or(Bpm::default().0, |(_, bpm)| bpm.0);
+    match bpms.find(|(_, bpm)| (bpm.0 - first).abs() > f64::EPSILON * first) {
+        Some((beat, _)) => Err(UltraStarError::VariableTempo(beat.clone())),
+        None => Ok(first),
+    }
+}
+
+/// Reads an UltraStar song. A beat of the score is four units, so the score has the tempo of
+/// `#BPM`. Each phrase becomes a track, and notes continuing a syllable (`~`) extend its
+/// mapping.
+pub fn read_ultrastar(
+    input: &str,
+    font_file: PathBuf,
+) -> Result<(Score, UltraStarMetadata), UltraStarError> {
+    let mut score = Score::new(font_file);
+    let mut metadata = UltraStarMetadata::default();
+    let mut bpm = None;
+    let mut relative = false;
+    // Start of the current phrase in units, for #RELATIVE files
+    let mut phrase_origin = BigInt::from(0);
+    let unit = BeatLength(BigRational::new(BigInt::one(), BigInt::from(4)));
+    let to_beat = |units: &BigInt| BeatPosition(BigRational::from_integer(units.clone()) * &unit.0);
+    let mut tracks = Vec::new();
+    let mut builder = TrackBuilder::new(BeatLength::one());
+
+    for (line_index, line) in input.lines().enumerate() {
+        let error = |message: String| UltraStarError::InvalidLine(line_index + 1, message);
+        let line = line.trim_end_matches('\r');
+        if let Some(header) = line.strip_prefix('#') {
+            let (key, value) = header.split_once(':').unwrap_or((header, ""));
+            let value = value.trim();
+            match key.trim().to_ascii_uppercase().as_str() {
+                "TITLE" => metadata.title = value.to_owned(),
+                "ARTIST" => metadata.artist = value.to_owned(),
+                "MP3" | "AUDIO" => metadata.audio = Some(value.to_owned()),
+                "BPM" => {
+                    let value = value
+                        .replace(',', ".")
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|bpm| bpm.is_finite() && *bpm > 0.0)
+                        .ok_or_else(|| error(format!("invalid BPM {:?}", value)))?;
+                    bpm = Some(value);
+                }
+                "GAP" => {
+                    let gap = value
+                        .replace(',', ".")
+                        .parse::<f64>()
+                        .map_err(|_| error(format!("invalid GAP {:?}", value)))?;
+                    score.offset = gap / 1000.0;
+                }
+                "RELATIVE" => relative = value.eq_ignore_ascii_case("yes"),
+                _ => {}
+            }
+            continue;
+        }
+        let kind = match line.chars().next() {
+            Some(kind) => kind,
+            None => continue,
+        };
+        let rest = &line[kind.len_utf8()..];
+        match kind {
+            ':' | '*' | 'F' | 'R' | 'G' => {
+                let mut fields = rest.trim_start().splitn(4, ' ');
+                let mut number = |name: &str| {
+                    fields
+                        .next()
+                        .and_then(|s| s.trim().parse::<BigInt>().ok())
+                        .ok_or_else(|| error(format!("invalid {} in note", name)))
+                };
+                let start = &phrase_origin + number("start")?;
+                let length = number("length")?;
+                number("pitch")?;
+                let text = fields.next().unwrap_or("");
+                let end = &start + length;
+                if text.trim() == "~" {
+                    builder.push_note(to_beat(&start), to_beat(&end));
+                } else {
+                    builder.push_syllable(text, to_beat(&start), to_beat(&end));
+                }
+            }
+            '-' => {
+                tracks.extend(
+                    std::mem::replace(&mut builder, TrackBuilder::new(BeatLength::one())).build(),
+                );
+                if relative {
+                    let shift = rest
+                        .split_whitespace()
+                        .last()
+                        .and_then(|s| s.parse::<BigInt>().ok())
+                        .ok_or_else(|| error("invalid line break".to_owned()))?;
+                    phrase_origin += shift;
+                }
+            }
+            'P' => {
+                tracks.extend(
+                    std::mem::replace(&mut builder, TrackBuilder::new(BeatLength::one())).build(),
+                );
+                phrase_origin = BigInt::from(0);
+            }
+            'E' => break,
+            _ => {}
+        }
+    }
+    tracks.extend(builder.build());
+
+    let bpm = bpm.ok_or(UltraStarError::MissingBpm)?;
+    score.bpms.insert(BeatPosition::zero(), Bpm(bpm));
+    score.lyrics = tracks
+        .iter()
+        .filter_map(|track| track.lyrics.as_ref())
+        .map(|lyrics| lyrics.text.trim())
+        .join("\n");
+    score.tracks = tracks.into_iter().collect();
+    Ok((score, metadata))
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test_util::sample_score;
+    use super::super::test_util::variable_score;
+    use super::read_ultrastar;
+    use super::write_ultrastar;
+    use super::UltraStarError;
+    use super::UltraStarMetadata;
+    use crate::schema::BeatPosition;
+    use crate::schema::Bpm;
+
+    #[test]
+    fn test_ultrastar_round_trip() {
+        let score = sample_score();
+        let metadata = UltraStarMetadata {
+            title: "Title".to_owned(),
+            artist: "Artist".to_owned(),
+            audio: None,
+        };
+        let mut out = Vec::new();
+        write_ultrastar(&score, &metadata, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(
+            out,
+            "#TITLE:Title\n#ARTIST:Artist\n#BPM:120\n#GAP:0\n\
+             : 0 4 0 Hello\n: 4 4 0  big\n: 12 4 0  world\nE\n"
+        );
+
+        let (imported, metadata) = read_ultrastar(&out, "font.otf".into()).unwrap();
+        assert_eq!(metadata.title, "Title");
+        assert_eq!(imported.tracks.len(), 1);
+        assert_eq!(imported.tracks[0].syllables(), score.tracks[0].syllables());
+        assert_eq!(imported.lyrics, "Hello big world");
+    }
+
+    #[test]
+    fn test_variable_tempo() {
+        let mut score = sample_score();
+        score.bpms.insert(
+            BeatPosition::from(num::BigRational::from_integer(8.into())),
+            Bpm(90.0),
+        );
+        let result = write_ultrastar(&score, &UltraStarMetadata::default(), Vec::new());
+        assert!(matches!(result, Err(UltraStarError::VariableTempo(_))));
+    }
+
+    #[test]
+    fn test_ultrastar_variable_score() {
+        let mut score = variable_score();
+        let change = BeatPosition::from(num::BigRational::from_integer(4.into()));
+        let result = write_ultrastar(&score, &UltraStarMetadata::default(), Vec::new());
+        match result {
+            Err(UltraStarError::VariableTempo(beat)) => assert_eq!(beat, change),
+            _ => panic!("expected a variable tempo error"),
+        }
+
+        // At a single tempo, the held note and the rest keep their lengths
+        score.bpms.remove(&change);
+        let mut out = Vec::new();
+        write_ultrastar(&score, &UltraStarMetadata::default(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.ends_with(": 0 4 0 Ça\n: 4 4 0  va\n: 12 8 0  très\n: 20 4 0  bien\nE\n"));
+        let (imported, _) = read_ultrastar(&out, "font.otf".into()).unwrap();
+        assert_eq!(imported.tracks[0].syllables(), score.tracks[0].syllables());
+        assert_eq!(imported.lyrics, "Ça va très bien");
+    }
+}
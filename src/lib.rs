@@ -1,11 +1,24 @@
+#[cfg(feature = "gui")]
 #[macro_use]
 pub mod druid_supplemental;
 
+#[cfg(feature = "gui")]
 pub mod audio;
 pub mod config;
+#[cfg(feature = "audio")]
 pub mod dasp_signal_ext;
+#[cfg(feature = "gui")]
 pub mod error;
+#[cfg(feature = "render")]
 pub mod fonts;
+pub mod formats;
 pub mod linest;
+#[cfg(feature = "audio")]
+pub mod metronome;
 pub mod schema;
+#[cfg(feature = "gui")]
 pub mod score_editor;
+#[cfg(feature = "audio")]
+pub mod time_stretch;
+#[cfg(feature = "render")]
+pub mod video;
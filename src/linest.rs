@@ -1,7 +1,9 @@
+#[cfg(feature = "gui")]
 use druid::Data;
 use std::ops::Range;
 
-#[derive(Clone, Copy, Default, Debug, Data)]
+#[derive(Clone, Copy, Default, Debug)]
+#[cfg_attr(feature = "gui", derive(Data))]
 pub struct Linest {
     x_sum: f64,
     x2_sum: f64,
@@ -11,7 +13,8 @@ pub struct Linest {
     n: usize,
 }
 
-#[derive(Clone, Copy, Debug, Data)]
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "gui", derive(Data))]
 pub struct LinestResult {
     pub a: f64,
     pub b: f64,
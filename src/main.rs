@@ -1,30 +1,70 @@
+use std::path::PathBuf;
+use std::sync::mpsc;
+
+use clap::Parser;
 use druid::AppLauncher;
 use druid::WindowDesc;
-use karaoke::audio::AudioCommand;
+use karaoke::audio::probe_music;
 use karaoke::audio::AudioManager;
 use karaoke::config::Config;
 use karaoke::error::EditorError;
 use karaoke::fonts::FontLoader;
 use karaoke::schema::Score;
 use karaoke::score_editor::build_toplevel_widget;
+use karaoke::score_editor::forward_audio_events;
 use karaoke::score_editor::ScoreEditorData;
 
+#[derive(Parser)]
+#[clap(about = "Karaoke timing editor")]
+struct Args {
+    /// Project file to open, or to save a new project to with `--new`
+    project: Option<PathBuf>,
+    /// Start a new project instead of opening the project file, which must not exist yet
+    #[clap(long)]
+    new: bool,
+    /// Music file to play, overriding the one recorded in the project
+    #[clap(short, long)]
+    music: Option<PathBuf>,
+    /// Config file to use
+    #[clap(short, long, default_value = "config.toml")]
+    config: PathBuf,
+}
+
 fn main() -> Result<(), EditorError> {
-    let config = Config::load()?;
+    let args = Args::parse();
+    let config = Config::load(&args.config)?;
+
+    let mut score = match &args.project {
+        Some(path) if args.new && path.exists() => {
+            return Err(EditorError::ProjectExists(path.clone()))
+        }
+        Some(path) if !args.new && !path.exists() => {
+            return Err(EditorError::ProjectNotFound(path.clone()))
+        }
+        Some(path) if !args.new => Score::load(path)?,
+        _ => Score::new(config.font_path),
+    };
+    if let Some(music) = args.music {
+        score.music = Some(probe_music(&music).map_err(EditorError::MusicLoadError)?);
+    }
 
-    let audio_manager = AudioManager::new()?;
-    if let Some(path) = std::env::args().nth(1) {
-        audio_manager
-            .command_sender()
-            .send(AudioCommand::LoadMusic(path.into()))
-            .unwrap();
+    let (event_sender, event_receiver) = mpsc::channel();
+    let (audio_manager, audio_status) = match AudioManager::new(event_sender) {
+        Ok(audio_manager) => (Some(audio_manager), String::new()),
+        Err(e) => (None, format!("Audio is disabled: {}", e)),
     };
     let font_loader = FontLoader::default();
-    let data = ScoreEditorData::new(Score::new(config.font_path));
-    let window = WindowDesc::new(build_toplevel_widget(audio_manager, font_loader))
-        .window_size((1440.0, 810.0));
-    AppLauncher::with_window(window)
-        .log_to_console()
-        .launch(data)?;
+    let mut data = ScoreEditorData::new(score);
+    data.project_path = args.project;
+    data.audio_status = audio_status;
+    let window = WindowDesc::new(build_toplevel_widget(
+        audio_manager,
+        config.playback,
+        font_loader,
+    ))
+    .window_size((1440.0, 810.0));
+    let launcher = AppLauncher::with_window(window).log_to_console();
+    forward_audio_events(event_receiver, launcher.get_external_handle());
+    launcher.launch(data)?;
     Ok(())
 }
@@ -0,0 +1,325 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::Seek;
+use std::io::Write;
+use std::iter;
+use std::iter::Peekable;
+use std::sync::Arc;
+
+use anyhow::bail;
+use dasp::signal;
+use dasp::signal::ConstHz;
+use dasp::signal::Delay;
+use dasp::signal::FromIterator;
+use dasp::signal::ScaleAmp;
+use dasp::signal::Sine;
+use dasp::signal::Take;
+use dasp::Signal;
+use hound::SampleFormat;
+use hound::WavSpec;
+use hound::WavWriter;
+use rodio::Decoder;
+use rodio::Source;
+
+use crate::dasp_signal_ext::Multiplexed;
+use crate::dasp_signal_ext::SignalExt;
+use crate::schema::iterate_beat_times;
+use crate::schema::BeatPosition;
+use crate::schema::Score;
+use crate::schema::Track;
+
+pub const DOWNBEAT_FREQUENCY: f64 = 1244.51;
+pub const BEAT_FREQUENCY: f64 = 739.99;
+
+#[derive(Clone, Copy, Debug)]
+pub struct SoundEffectSchedule {
+    pub time: f64,
+    pub frequency: f64,
+}
+pub type SESchedulesBox = Box<dyn Iterator<Item = SoundEffectSchedule> + Send>;
+
+type SoundEffect = Multiplexed<Delay<FromIterator<Take<ScaleAmp<Sine<ConstHz>>>>>>;
+
+/// A click on every beat from `start_beat`, accented at the start of each measure.
+pub fn metronome_schedules(score: &Score, start_beat: BeatPosition) -> SESchedulesBox {
+    Box::new(
+        iterate_beat_times(
+            score.offset,
+            score.measure_lengths.clone(),
+            score.bpms.clone(),
+            start_beat,
+        )
+        .map(|(first, time)| SoundEffectSchedule {
+            time,
+            frequency: if first {
+                DOWNBEAT_FREQUENCY
+            } else {
+                BEAT_FREQUENCY
+            },
+        }),
+    )
+}
+
+/// The clicks of `metronome_schedules` from `start_beat` up to the time `end`, to be played
+/// again and again without computing them each time.
+pub fn metronome_schedule_list(
+    score: &Score,
+    start_beat: BeatPosition,
+    end: f64,
+) -> Arc<[SoundEffectSchedule]> {
+    metronome_schedules(score, start_beat)
+        .take_while(|s| s.time < end)
+        .collect()
+}
+
+enum Schedules {
+    Iter(Peekable<SESchedulesBox>),
+    /// The list and the index of the next schedule
+    List(Arc<[SoundEffectSchedule]>, usize),
+}
+
+impl Schedules {
+    fn peek(&mut self) -> Option<&SoundEffectSchedule> {
+        match self {
+            Schedules::Iter(iter) => iter.peek(),
+            Schedules::List(list, index) => list.get(*index),
+        }
+    }
+
+    fn advance(&mut self) {
+        match self {
+            Schedules::Iter(iter) => {
+                iter.next();
+            }
+            Schedules::List(_, index) => *index += 1,
+        }
+    }
+}
+
+/// Turns schedules into interleaved samples of short sine blips.
+pub struct SoundEffectMixer {
+    sample_rate: u32,
+    channels: u16,
+    volume: f64,
+    speed: f64,
+    schedules: Schedules,
+    sound_effects: VecDeque<SoundEffect>,
+}
+
+impl SoundEffectMixer {
+    pub fn new(sample_rate: u32, channels: u16) -> Self {
+        Self {
+            sample_rate,
+            channels,
+            volume: 0.0,
+            speed: 1.0,
+            schedules: Self::empty_schedules(),
+            sound_effects: VecDeque::new(),
+        }
+    }
+
+    pub fn set_volume(&mut self, volume: f64) {
+        self.volume = volume;
+    }
+
+    /// Song seconds per real second. The sound effects themselves keep their length and pitch.
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed;
+    }
+
+    pub fn set_schedules(&mut self, schedules: SESchedulesBox) {
+        self.schedules = Schedules::Iter(schedules.peekable());
+    }
+
+    /// Plays the list from its start. Only clones the `Arc`, so this can be done on every loop.
+    pub fn set_schedule_list(&mut self, schedules: &Arc<[SoundEffectSchedule]>) {
+        self.schedules = Schedules::List(schedules.clone(), 0);
+    }
+
+    /// Drops the pending schedules and the sound effects being played.
+    pub fn clear(&mut self) {
+        self.schedules = Self::empty_schedules();
+        self.sound_effects.clear();
+    }
+
+    /// Starts the sound effects scheduled before `end`, for the samples starting at time `start`,
+    /// both in song time.
+    pub fn schedule(&mut self, start: f64, end: f64) {
+        while let Some(next) = self.schedules.peek() {
+            if end <= next.time {
+                break;
+            }
+            let next = *next;
+            self.schedules.advance();
+            let wave = signal::from_iter(
+                signal::rate(self.sample_rate as _)
+                    .const_hz(next.frequency)
+                    .sine()
+                    .scale_amp(self.volume)
+                    .take(self.sample_rate as usize / 20), // 0.05 seconds
+            )
+            .delay(((next.time - start).max(0.0) / self.speed * self.sample_rate as f64) as _)
+            .multiplexed(self.channels as _);
+            self.sound_effects.push_back(wave);
+        }
+
+        self.sound_effects.retain(|x| !x.is_exhausted());
+    }
+
+    /// The next interleaved sample of all the sound effects being played.
+    pub fn next_sample(&mut self) -> f64 {
+        self.sound_effects.iter_mut().map(|x| x.next()).sum()
+    }
+
+    fn empty_schedules() -> Schedules {
+        let ret: SESchedulesBox = Box::new(iter::empty());
+        Schedules::Iter(ret.peekable())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MetronomeRenderOptions {
+    pub metronome_volume: f64,
+    /// Mixes the music of the score at this volume, if any.
+    pub music_volume: Option<f64>,
+}
+
+impl Default for MetronomeRenderOptions {
+    fn default() -> Self {
+        Self {
+            metronome_volume: 0.4,
+            music_volume: None,
+        }
+    }
+}
+
+const SAMPLE_RATE: u32 = 44100;
+const CHANNELS: u16 = 2;
+/// Frames mixed at a time, like a buffer of the audio device
+const BLOCK_FRAMES: u64 = 1024;
+
+/// Renders the metronome as the editor plays it into a 16-bit WAV file, over the music, or up to
+/// the end of the last track if the score has no music.
+/// With music, the file has the sample rate and channels of the music.
+pub fn render_metronome(
+    score: &Score,
+    options: &MetronomeRenderOptions,
+    out: impl Write + Seek,
+) -> anyhow::Result<()> {
+    let mut music: Option<Box<dyn Iterator<Item = f32>>> = None;
+    let (sample_rate, channels, end_frame) = match (&score.music, options.music_volume) {
+        (Some(info), Some(_)) => {
+            let decoder = Decoder::new(BufReader::new(File::open(&info.path)?))?;
+            let format = (decoder.sample_rate(), decoder.channels(), None);
+            music = Some(Box::new(decoder.convert_samples::<f32>()));
+            format
+        }
+        (_, Some(_)) => bail!("the score has no music to mix"),
+        (info, None) => {
+            let duration = match info {
+                Some(info) => info.duration,
+                None => score
+                    .tracks
+                    .iter()
+                    .map(Track::end_beat)
+                    .max()
+                    .map_or(0.0, |end| score.beat_to_time(&end)),
+            };
+            let end_frame = (duration * SAMPLE_RATE as f64).ceil() as u64;
+            (SAMPLE_RATE, CHANNELS, Some(end_frame))
+        }
+    };
+    let music_volume = options.music_volume.unwrap_or(0.0);
+
+    let mut mixer = SoundEffectMixer::new(sample_rate, channels);
+    mixer.set_volume(options.metronome_volume);
+    // Clicks before the start of the music would all sound at once at the start of the file
+    mixer.set_schedules(Box::new(
+        metronome_schedules(score, BeatPosition::zero()).filter(|s| s.time >= 0.0),
+    ));
+
+    let spec = WavSpec {
+        channels,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: SampleFormat::Int,
+    };
+    let mut writer = WavWriter::new(out, spec)?;
+    let mut frame = 0;
+    'render: loop {
+        let time = |frame: u64| frame as f64 / sample_rate as f64;
+        mixer.schedule(time(frame), time(frame + BLOCK_FRAMES));
+        for _ in 0..BLOCK_FRAMES {
+            if matches!(end_frame, Some(end) if frame >= end) {
+                break 'render;
+            }
+            for _ in 0..channels {
+                let next = match &mut music {
+                    Some(music) => match music.next() {
+                        Some(next) => next,
+                        None => break 'render,
+                    },
+                    None => 0.0,
+                };
+                let mut next = next * music_volume as f32;
+                next += mixer.next_sample() as f32;
+                let next = next.clamp(-1.0, 1.0);
+                writer.write_sample((next * i16::MAX as f32) as i16)?;
+            }
+            frame += 1;
+        }
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use hound::WavReader;
+
+    use super::metronome_schedule_list;
+    use super::render_metronome;
+    use super::MetronomeRenderOptions;
+    use super::CHANNELS;
+    use super::SAMPLE_RATE;
+    use crate::formats::test_util::sample_score;
+    use crate::schema::BeatPosition;
+
+    #[test]
+    fn test_render_metronome() {
+        let score = sample_score();
+        let mut out = Cursor::new(Vec::new());
+        render_metronome(&score, &MetronomeRenderOptions::default(), &mut out).unwrap();
+
+        out.set_position(0);
+        let reader = WavReader::new(out).unwrap();
+        assert_eq!(reader.spec().sample_rate, SAMPLE_RATE);
+        assert_eq!(reader.spec().channels, CHANNELS);
+        // The track ends at 2 seconds, with a click every half second
+        assert_eq!(reader.duration(), 2 * SAMPLE_RATE);
+        let samples = reader
+            .into_samples::<i16>()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        let frame_at = |time: f64| (time * SAMPLE_RATE as f64) as usize * CHANNELS as usize;
+        for &time in &[0.0, 0.5, 1.0, 1.5] {
+            let click = &samples[frame_at(time)..frame_at(time + 0.05)];
+            assert!(click.iter().any(|&s| s > 1000));
+        }
+        assert!(samples[frame_at(0.1)..frame_at(0.5)]
+            .iter()
+            .all(|&s| s == 0));
+    }
+
+    #[test]
+    fn test_metronome_schedule_list() {
+        let score = sample_score();
+        let list = metronome_schedule_list(&score, BeatPosition::zero(), 1.5);
+        // A click every half second, up to but not at the end
+        let times = list.iter().map(|s| s.time).collect::<Vec<_>>();
+        assert_eq!(times, vec![0.0, 0.5, 1.0]);
+    }
+}
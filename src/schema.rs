@@ -1,31 +1,48 @@
+mod file_format;
+
 use std::borrow::Borrow;
 use std::cmp::Ordering::*;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::Read;
 use std::iter;
 use std::ops::Add;
 use std::ops::AddAssign;
 use std::ops::Mul;
 use std::ops::Sub;
 use std::ops::SubAssign;
+use std::path::Path;
 use std::path::PathBuf;
 
 use derive_more::From;
 use derive_new::new;
-use druid::im::OrdMap;
-use druid::im::OrdSet;
-use druid::im::Vector;
+#[cfg(feature = "gui")]
 use druid::Data;
+#[cfg(feature = "gui")]
 use druid::Lens;
+use im::OrdMap;
+use im::OrdSet;
+use im::Vector;
 use itertools::Itertools;
 use num::rational::BigRational;
 use num::BigInt;
 use num::One;
+use num::Signed;
 use num::ToPrimitive;
 use num::Zero;
+use sha2::Digest;
+use sha2::Sha256;
+use thiserror::Error;
+
+use self::file_format::ScoreFile;
 
-#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, From, Debug, derive_more::Display, Data)]
-pub struct BeatPosition(#[data(eq)] pub BigRational);
-#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, From, Debug, derive_more::Display, Data)]
-pub struct BeatLength(#[data(eq)] pub BigRational);
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, From, Debug, derive_more::Display)]
+#[cfg_attr(feature = "gui", derive(Data))]
+pub struct BeatPosition(#[cfg_attr(feature = "gui", data(eq))] pub BigRational);
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, From, Debug, derive_more::Display)]
+#[cfg_attr(feature = "gui", derive(Data))]
+pub struct BeatLength(#[cfg_attr(feature = "gui", data(eq))] pub BigRational);
 
 impl BeatPosition {
     pub fn zero() -> Self {
@@ -118,13 +135,15 @@ impl Sub<&BeatPosition> for &BeatPosition {
 #[derive(Clone, Debug, derive_more::From, derive_more::FromStr, derive_more::Display)]
 pub struct BigIntData(BigInt);
 
+#[cfg(feature = "gui")]
 impl Data for BigIntData {
     fn same(&self, other: &Self) -> bool {
         self.0 == other.0
     }
 }
 
-#[derive(Clone, Debug, derive_more::Display, Data, Lens)]
+#[derive(Clone, Debug, derive_more::Display)]
+#[cfg_attr(feature = "gui", derive(Data, Lens))]
 #[display(fmt = "{}/{}", numerator, denominator)]
 pub struct MeasureLength {
     numerator: BigIntData,
@@ -164,9 +183,15 @@ impl MeasureLength {
     pub fn four() -> Self {
         BeatLength::four().into()
     }
+
+    /// The numerator and denominator, as in a time signature.
+    pub fn fraction(&self) -> (&BigInt, &BigInt) {
+        (&self.numerator.0, &self.denominator.0)
+    }
 }
 
-#[derive(Clone, Copy, Debug, derive_more::FromStr, derive_more::Display, Data)]
+#[derive(Clone, Copy, Debug, derive_more::FromStr, derive_more::Display)]
+#[cfg_attr(feature = "gui", derive(Data))]
 pub struct Bpm(pub f64);
 
 impl Default for Bpm {
@@ -213,7 +238,8 @@ impl Mul<BeatLength> for Bpm {
     }
 }
 
-#[derive(Clone, Debug, new, Data, Lens)]
+#[derive(Clone, Debug, new)]
+#[cfg_attr(feature = "gui", derive(Data, Lens))]
 pub struct Score {
     #[new(default)]
     pub tracks: Vector<Track>,
@@ -225,8 +251,51 @@ pub struct Score {
     pub offset: f64,
     #[new(default)]
     pub lyrics: String,
-    #[data(eq)]
+    #[cfg_attr(feature = "gui", data(eq))]
     pub font_file: PathBuf,
+    #[new(default)]
+    pub music: Option<MusicInfo>,
+}
+
+/// The music a score is timed against.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "gui", derive(Data))]
+pub struct MusicInfo {
+    /// Absolute while loaded; recorded relative to the project file on save.
+    #[cfg_attr(feature = "gui", data(eq))]
+    pub path: PathBuf,
+    pub duration: f64,
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// SHA-256 of the file contents, in lowercase hex. Empty until the file has been read
+    /// through by `audio::measure_music`.
+    pub hash: String,
+}
+
+impl MusicInfo {
+    pub fn hash_file(path: impl AsRef<Path>) -> std::io::Result<String> {
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut BufReader::new(File::open(path)?), &mut hasher)?;
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Checks whether the file at `path` still has the contents the score was timed against.
+    pub fn verify(&self) -> std::io::Result<MusicStatus> {
+        if !self.path.exists() {
+            return Ok(MusicStatus::Missing);
+        }
+        Ok(match Self::hash_file(&self.path)? {
+            hash if hash == self.hash => MusicStatus::Unchanged,
+            _ => MusicStatus::Changed,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MusicStatus {
+    Unchanged,
+    Changed,
+    Missing,
 }
 
 impl Score {
@@ -236,6 +305,135 @@ impl Score {
     pub fn time_to_beat(&self, time: f64) -> f64 {
         time_to_beat(self.offset, &self.bpms, time)
     }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Score, ProjectLoadError> {
+        let path = path.as_ref();
+        let mut s = String::new();
+        BufReader::new(File::open(path)?).read_to_string(&mut s)?;
+        let mut score = Self::from_project_str(&s)?;
+        if let Some(music) = &mut score.music {
+            music.path = project_dir(path).join(&music.path);
+        }
+        Ok(score)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ProjectSaveError> {
+        let path = path.as_ref();
+        let mut score = self.clone();
+        if let Some(music) = &mut score.music {
+            music.path = relative_path(&music.path, project_dir(path));
+        }
+        std::fs::write(path, score.to_project_string()?)?;
+        Ok(())
+    }
+
+    pub fn from_project_str(s: &str) -> Result<Score, ProjectLoadError> {
+        Score::try_from(toml::from_str::<ScoreFile>(s)?)
+    }
+
+    pub fn to_project_string(&self) -> Result<String, ProjectSaveError> {
+        Ok(toml::to_string(&ScoreFile::from(self))?)
+    }
+
+    /// Lists the problems that would make exports wrong. Tracks are numbered from zero.
+    pub fn validate(&self) -> Vec<ValidationError> {
+        let mut errors = Vec::new();
+        for (beat, bpm) in &self.bpms {
+            if !(bpm.0.is_finite() && bpm.0 > 0.0) {
+                errors.push(ValidationError::InvalidBpm(beat.clone(), *bpm));
+            }
+        }
+        for (beat, length) in &self.measure_lengths {
+            let (numerator, denominator) = length.fraction();
+            if !(numerator.is_positive() && denominator.is_positive()) {
+                errors.push(ValidationError::InvalidMeasureLength(
+                    beat.clone(),
+                    length.clone(),
+                ));
+            }
+        }
+        for (i, track) in self.tracks.iter().enumerate() {
+            for (j, element) in track.elements.iter().enumerate() {
+                if !element.length.0.is_positive() {
+                    errors.push(ValidationError::InvalidElementLength(
+                        i,
+                        j,
+                        element.length.clone(),
+                    ));
+                }
+            }
+            let lyrics = match &track.lyrics {
+                Some(lyrics) => lyrics,
+                None => continue,
+            };
+            let char_count = lyrics.text.chars().count();
+            let mut last_end = 0;
+            for (&(start, end), &count) in &lyrics.mappings {
+                if !(last_end <= start && start < end && end <= char_count) {
+                    errors.push(ValidationError::InvalidMapping(i, start, end));
+                } else if count == 0 {
+                    errors.push(ValidationError::EmptyMapping(i, start, end));
+                }
+                last_end = last_end.max(end);
+            }
+            let mapped = lyrics.mappings.values().sum::<usize>();
+            let notes = track.iterate_notes().count();
+            if mapped > notes {
+                errors.push(ValidationError::TooManyMappedNotes(i, mapped, notes));
+            }
+        }
+        errors
+    }
+}
+
+fn project_dir(project_path: &Path) -> &Path {
+    match project_path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    }
+}
+
+fn relative_path(path: &Path, base: &Path) -> PathBuf {
+    match (path.canonicalize(), base.canonicalize()) {
+        (Ok(path), Ok(base)) => pathdiff::diff_paths(&path, &base).unwrap_or(path),
+        _ => path.to_owned(),
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ProjectLoadError {
+    #[error("{0}")]
+    IOError(#[from] std::io::Error),
+    #[error("{0}")]
+    IllegalProjectFile(#[from] toml::de::Error),
+    #[error("Unsupported project format version: {0}")]
+    UnsupportedVersion(u32),
+    #[error("{0}")]
+    InvalidValue(String),
+}
+
+#[derive(Debug, Error)]
+pub enum ValidationError {
+    #[error("BPM at beat {0} must be positive, found {1}")]
+    InvalidBpm(BeatPosition, Bpm),
+    #[error("measure length at beat {0} must be positive, found {1}")]
+    InvalidMeasureLength(BeatPosition, MeasureLength),
+    #[error("track {0}: element {1} must have a positive length, found {2}")]
+    InvalidElementLength(usize, usize, BeatLength),
+    #[error("track {0}: lyrics mapping {1}..{2} is outside of the text or overlaps another")]
+    InvalidMapping(usize, usize, usize),
+    #[error("track {0}: lyrics mapping {1}..{2} is mapped to no notes")]
+    EmptyMapping(usize, usize, usize),
+    #[error("track {0}: lyrics are mapped to {1} notes, but the track has only {2}")]
+    TooManyMappedNotes(usize, usize, usize),
+}
+
+#[derive(Debug, Error)]
+pub enum ProjectSaveError {
+    #[error("{0}")]
+    IOError(#[from] std::io::Error),
+    #[error("{0}")]
+    SerializeError(#[from] toml::ser::Error),
 }
 pub fn beat_to_time(offset: f64, bpms: &OrdMap<BeatPosition, Bpm>, pos: &BeatPosition) -> f64 {
     let mut time = offset;
@@ -288,16 +486,20 @@ pub fn time_to_beat(offset: f64, bpms: &OrdMap<BeatPosition, Bpm>, time: f64) ->
     last_beat + (time - cur_time) / bpm.beat_length()
 }
 
-#[derive(Clone, Debug, Data)]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "gui", derive(Data))]
 pub struct Track {
     pub start_beat: BeatPosition,
     pub elements: Vector<ScoreElement>,
     pub lyrics: Option<Lyrics>,
 }
 
-#[derive(Clone, Debug, Data)]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "gui", derive(Data))]
 pub struct Lyrics {
     pub text: String,
+    /// The number of notes each range of `text` is sung over. Ranges are character indices, not
+    /// glyph indices of the rendered text, so they don't depend on the font.
     pub mappings: OrdMap<(usize, usize), usize>,
 }
 
@@ -321,13 +523,15 @@ impl Track {
     }
 }
 
-#[derive(Clone, PartialEq, Debug, Data)]
+#[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "gui", derive(Data))]
 pub struct ScoreElement {
     pub kind: ScoreElementKind,
     pub length: BeatLength,
 }
 
-#[derive(Clone, Copy, PartialEq, Debug, Data)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "gui", derive(Data))]
 pub enum ScoreElementKind {
     Start,
     Stop,
@@ -362,6 +566,51 @@ impl Track {
             Some((beat, end_beat, note))
         })
     }
+
+    /// Splits the lyrics by the mapping ranges and pairs each piece with the notes it is sung
+    /// over, in order.
+    /// Text outside of any mapping is attached to the following piece, or to the last one.
+    /// Lyrics without mappings are sung over all notes of the track.
+    pub fn syllables(&self) -> Vec<Syllable<'_>> {
+        let lyrics = match &self.lyrics {
+            Some(lyrics) => lyrics,
+            None => return Vec::new(),
+        };
+        let text = &lyrics.text;
+        let offsets = text
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain(iter::once(text.len()))
+            .collect_vec();
+        let char_count = offsets.len() - 1;
+        let mut notes = self.iterate_notes().map(|(start, end, _)| (start, end));
+
+        let mut ranges = Vec::new();
+        let mut last_end = 0;
+        for (&(_, end), &count) in &lyrics.mappings {
+            let end = end.min(char_count).max(last_end);
+            ranges.push((last_end, end, notes.by_ref().take(count).collect_vec()));
+            last_end = end;
+        }
+        match ranges.last_mut() {
+            Some(last) => last.1 = char_count,
+            None => ranges.push((0, char_count, notes.collect())),
+        }
+        ranges
+            .into_iter()
+            .map(|(start, end, notes)| Syllable {
+                text: &text[offsets[start]..offsets[end]],
+                notes,
+            })
+            .collect()
+    }
+}
+
+/// A piece of lyrics together with the (start, end) beats of the notes it is sung over.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Syllable<'a> {
+    pub text: &'a str,
+    pub notes: Vec<(BeatPosition, BeatPosition)>,
 }
 
 pub fn iterate_measures<'a, BP, ML>(
@@ -400,6 +649,25 @@ where
     })
 }
 
+/// The `count`-th bar line before `beat`, or `beat` itself if `count` is 0. Stops at beat 0.
+pub fn bar_line_before(
+    measures: &OrdMap<BeatPosition, MeasureLength>,
+    beat: &BeatPosition,
+    count: usize,
+) -> BeatPosition {
+    if count == 0 {
+        return beat.clone();
+    }
+    let bar_lines = iterate_measures(measures.iter())
+        .map(|(start, _)| start)
+        .take_while(|start| start < beat)
+        .collect_vec();
+    bar_lines
+        .get(bar_lines.len().saturating_sub(count))
+        .cloned()
+        .unwrap_or_else(|| beat.clone())
+}
+
 pub fn iterate_beat_times(
     offset: f64,
     measures: OrdMap<BeatPosition, MeasureLength>,
@@ -454,13 +722,23 @@ pub fn iterate_beat_times(
 mod test {
     use std::iter;
 
+    use super::bar_line_before;
     use super::beat_to_time;
     use super::iterate_beat_times;
     use super::iterate_measures;
+    use super::BeatLength;
     use super::BeatPosition;
     use super::Bpm;
+    use super::Lyrics;
     use super::MeasureLength;
-    use druid::im::ordmap;
+    use super::MusicInfo;
+    use super::ProjectLoadError;
+    use super::Score;
+    use super::ScoreElement;
+    use super::ScoreElementKind;
+    use super::Track;
+    use super::ValidationError;
+    use im::ordmap;
     use itertools::iterate;
     use itertools::Itertools;
     use num::BigRational;
@@ -516,6 +794,19 @@ mod test {
         assert_eq!(got, expected);
     }
 
+    #[test]
+    fn test_bar_line_before() {
+        let measures = ordmap![
+            bp!(8) => MeasureLength::new(3, 4)
+        ];
+        assert_eq!(bar_line_before(&measures, &bp!(13), 0), bp!(13));
+        assert_eq!(bar_line_before(&measures, &bp!(13), 1), bp!(11));
+        assert_eq!(bar_line_before(&measures, &bp!(13), 2), bp!(8));
+        assert_eq!(bar_line_before(&measures, &bp!(11), 2), bp!(4));
+        assert_eq!(bar_line_before(&measures, &bp!(5), 3), bp!(0));
+        assert_eq!(bar_line_before(&measures, &bp!(0), 2), bp!(0));
+    }
+
     #[test]
     fn test_iterate_beat_times_01() {
         let measures = ordmap![];
@@ -678,4 +969,176 @@ mod test {
         ];
         assert_eq!(got, expected);
     }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn test_project_round_trip() {
+        let mut score = Score::new("fonts/font.otf".into());
+        score.offset = 1.25;
+        score.lyrics = "ABC\nDEF".to_owned();
+        score.music = Some(MusicInfo {
+            path: "music/song.ogg".into(),
+            duration: 183.5,
+            sample_rate: 44100,
+            channels: 2,
+            hash: "0123abcd".to_owned(),
+        });
+        score.bpms.insert(bp!(0), Bpm(150.5));
+        score.bpms.insert(
+            BeatPosition::from(BigRational::new(41.into(), 3.into())),
+            Bpm(75.0),
+        );
+        score
+            .measure_lengths
+            .insert(bp!(16), MeasureLength::new(6, 8));
+        score.tracks.push_back(Track {
+            start_beat: BeatPosition::from(BigRational::new(7.into(), 2.into())),
+            elements: vec![
+                (
+                    ScoreElementKind::Start,
+                    BigRational::new(1.into(), 3.into()),
+                ),
+                (ScoreElementKind::Skip, BigRational::new(2.into(), 3.into())),
+                (ScoreElementKind::Stop, BigRational::new(1.into(), 4.into())),
+            ]
+            .into_iter()
+            .map(|(kind, length)| ScoreElement {
+                kind,
+                length: BeatLength::from(length),
+            })
+            .collect(),
+            lyrics: Some(Lyrics {
+                text: "ABC".to_owned(),
+                mappings: ordmap![(0, 1) => 1, (1, 3) => 2],
+            }),
+        });
+        score.tracks.push_back(Track {
+            start_beat: bp!(20),
+            elements: Default::default(),
+            lyrics: None,
+        });
+
+        let saved = score.to_project_string().unwrap();
+        let loaded = Score::from_project_str(&saved).unwrap();
+        assert_eq!(loaded.font_file, score.font_file);
+        assert_eq!(loaded.offset, score.offset);
+        assert_eq!(loaded.lyrics, score.lyrics);
+        assert_eq!(
+            loaded.music.as_ref().map(|m| (
+                &m.path,
+                m.duration,
+                m.sample_rate,
+                m.channels,
+                &m.hash
+            )),
+            score
+                .music
+                .as_ref()
+                .map(|m| (&m.path, m.duration, m.sample_rate, m.channels, &m.hash)),
+        );
+        assert_eq!(
+            loaded.bpms.iter().map(|(b, bpm)| (b, bpm.0)).collect_vec(),
+            score.bpms.iter().map(|(b, bpm)| (b, bpm.0)).collect_vec(),
+        );
+        assert_eq!(
+            loaded
+                .measure_lengths
+                .iter()
+                .map(|(b, m)| (b, m.to_string()))
+                .collect_vec(),
+            vec![(&bp!(16), "6/8".to_owned())],
+        );
+        assert_eq!(loaded.tracks.len(), 2);
+        for (loaded, track) in loaded.tracks.iter().zip(&score.tracks) {
+            assert_eq!(loaded.start_beat, track.start_beat);
+            assert_eq!(loaded.elements, track.elements);
+            assert_eq!(
+                loaded.lyrics.as_ref().map(|l| (&l.text, &l.mappings)),
+                track.lyrics.as_ref().map(|l| (&l.text, &l.mappings)),
+            );
+        }
+    }
+
+    #[test]
+    fn test_project_versions() {
+        let saved = Score::new("font.otf".into()).to_project_string().unwrap();
+        assert!(saved.contains("format_version = 2"));
+        // Mappings of version 1 are read as they are
+        let old = saved.replace("format_version = 2", "format_version = 1");
+        assert!(Score::from_project_str(&old).is_ok());
+        let new = saved.replace("format_version = 2", "format_version = 3");
+        assert!(matches!(
+            Score::from_project_str(&new),
+            Err(ProjectLoadError::UnsupportedVersion(3))
+        ));
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut score = Score::new("font.otf".into());
+        score.bpms.insert(bp!(0), Bpm(120.0));
+        score.tracks.push_back(Track {
+            start_beat: bp!(0),
+            elements: vec![
+                (ScoreElementKind::Start, 1),
+                (ScoreElementKind::Stop, 1),
+                (ScoreElementKind::Start, 1),
+            ]
+            .into_iter()
+            .map(|(kind, length)| ScoreElement {
+                kind,
+                length: BeatLength::from(BigRational::from_integer(length.into())),
+            })
+            .collect(),
+            lyrics: Some(Lyrics {
+                text: "ABC".to_owned(),
+                mappings: ordmap![(0, 1) => 1, (1, 3) => 1],
+            }),
+        });
+        assert!(score.validate().is_empty());
+
+        score.bpms.insert(bp!(4), Bpm(0.0));
+        score
+            .measure_lengths
+            .insert(bp!(0), MeasureLength::new(0, 4));
+        score.tracks[0].elements[1].length = BeatLength::from(BigRational::from_integer(0.into()));
+        score.tracks[0].lyrics = Some(Lyrics {
+            text: "ABC".to_owned(),
+            mappings: ordmap![(0, 2) => 1, (1, 3) => 1, (3, 5) => 0],
+        });
+        let errors = score.validate();
+        assert!(matches!(errors[0], ValidationError::InvalidBpm(_, _)));
+        assert!(matches!(
+            errors[1],
+            ValidationError::InvalidMeasureLength(_, _)
+        ));
+        assert!(matches!(
+            errors[2],
+            ValidationError::InvalidElementLength(0, 1, _)
+        ));
+        assert!(matches!(
+            errors[3],
+            ValidationError::InvalidMapping(0, 1, 3)
+        ));
+        assert!(matches!(
+            errors[4],
+            ValidationError::InvalidMapping(0, 3, 5)
+        ));
+        assert_eq!(errors.len(), 5);
+
+        score.bpms.clear();
+        score.measure_lengths.clear();
+        score.tracks[0].elements.pop_back();
+        score.tracks[0].lyrics = Some(Lyrics {
+            text: "ABC".to_owned(),
+            mappings: ordmap![(0, 1) => 2, (1, 3) => 0],
+        });
+        let errors = score.validate();
+        assert!(matches!(errors[1], ValidationError::EmptyMapping(0, 1, 3)));
+        assert!(matches!(
+            errors[2],
+            ValidationError::TooManyMappedNotes(0, 2, 1)
+        ));
+        assert_eq!(errors.len(), 3);
+    }
 }
@@ -0,0 +1,312 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use im::OrdMap;
+use num::BigInt;
+use num::BigRational;
+use num::Zero;
+use serde::de;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+
+use super::BeatLength;
+use super::BeatPosition;
+use super::BigIntData;
+use super::Bpm;
+use super::Lyrics;
+use super::MeasureLength;
+use super::MusicInfo;
+use super::ProjectLoadError;
+use super::Score;
+use super::ScoreElement;
+use super::ScoreElementKind;
+use super::Track;
+
+/// Version 2 records lyrics mappings as character indices into the text. Version 1 recorded
+/// glyph indices of the lyrics rendered in the project font. It is still read, taking them as
+/// character indices, which they equal unless the font forms ligatures or draws combining marks
+/// together with their base. Mappings over such lyrics should be checked in the mapping dialog
+/// after opening an older project.
+pub const FORMAT_VERSION: u32 = 2;
+/// The oldest version that can still be read
+const MIN_FORMAT_VERSION: u32 = 1;
+
+// Tables must come after plain values in TOML, so the field order below matters.
+#[derive(Serialize, Deserialize)]
+pub struct ScoreFile {
+    format_version: u32,
+    offset: f64,
+    font_file: PathBuf,
+    lyrics: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    music: Option<MusicEntry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    bpms: Vec<BpmEntry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    measure_lengths: Vec<MeasureLengthEntry>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tracks: Vec<TrackEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MusicEntry {
+    path: PathBuf,
+    duration: f64,
+    sample_rate: u32,
+    channels: u16,
+    sha256: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BpmEntry {
+    beat: Rational,
+    bpm: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MeasureLengthEntry {
+    beat: Rational,
+    length: Fraction,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TrackEntry {
+    start_beat: Rational,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    elements: Vec<ElementEntry>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lyrics: Option<LyricsEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ElementEntry {
+    kind: ElementKindEntry,
+    length: Rational,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ElementKindEntry {
+    Start,
+    Stop,
+    Skip,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LyricsEntry {
+    text: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    mappings: Vec<MappingEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MappingEntry {
+    start: usize,
+    end: usize,
+    notes: usize,
+}
+
+/// An exact rational number, written as `"numerator/denominator"`.
+struct Rational(BigRational);
+
+impl Serialize for Rational {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!("{}/{}", self.0.numer(), self.0.denom()))
+    }
+}
+
+impl<'de> Deserialize<'de> for Rational {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        let Fraction(numer, denom) = s.parse().map_err(de::Error::custom)?;
+        if denom.is_zero() {
+            return Err(de::Error::custom(format!("zero denominator in {:?}", s)));
+        }
+        Ok(Rational(BigRational::new(numer, denom)))
+    }
+}
+
+/// A fraction that is kept unreduced, e.g. `6/8` for measure lengths.
+struct Fraction(BigInt, BigInt);
+
+impl fmt::Display for Fraction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.0, self.1)
+    }
+}
+
+impl FromStr for Fraction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("expected a fraction like \"3/4\", found {:?}", s);
+        let (numer, denom) = match s.split_once('/') {
+            Some((numer, denom)) => (numer.trim(), denom.trim()),
+            None => (s.trim(), "1"),
+        };
+        Ok(Fraction(
+            numer.parse().map_err(|_| error())?,
+            denom.parse().map_err(|_| error())?,
+        ))
+    }
+}
+
+impl Serialize for Fraction {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Fraction {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl From<&Score> for ScoreFile {
+    fn from(score: &Score) -> Self {
+        Self {
+            format_version: FORMAT_VERSION,
+            offset: score.offset,
+            font_file: score.font_file.clone(),
+            music: score.music.as_ref().map(|music| MusicEntry {
+                path: music.path.clone(),
+                duration: music.duration,
+                sample_rate: music.sample_rate,
+                channels: music.channels,
+                sha256: music.hash.clone(),
+            }),
+            lyrics: score.lyrics.clone(),
+            bpms: score
+                .bpms
+                .iter()
+                .map(|(beat, bpm)| BpmEntry {
+                    beat: Rational(beat.0.clone()),
+                    bpm: bpm.0,
+                })
+                .collect(),
+            measure_lengths: score
+                .measure_lengths
+                .iter()
+                .map(|(beat, length)| MeasureLengthEntry {
+                    beat: Rational(beat.0.clone()),
+                    length: Fraction(length.numerator.0.clone(), length.denominator.0.clone()),
+                })
+                .collect(),
+            tracks: score.tracks.iter().map(TrackEntry::from).collect(),
+        }
+    }
+}
+
+impl From<&Track> for TrackEntry {
+    fn from(track: &Track) -> Self {
+        Self {
+            start_beat: Rational(track.start_beat.0.clone()),
+            elements: track
+                .elements
+                .iter()
+                .map(|element| ElementEntry {
+                    kind: match element.kind {
+                        ScoreElementKind::Start => ElementKindEntry::Start,
+                        ScoreElementKind::Stop => ElementKindEntry::Stop,
+                        ScoreElementKind::Skip => ElementKindEntry::Skip,
+                    },
+                    length: Rational(element.length.0.clone()),
+                })
+                .collect(),
+            lyrics: track.lyrics.as_ref().map(|lyrics| LyricsEntry {
+                text: lyrics.text.clone(),
+                mappings: lyrics
+                    .mappings
+                    .iter()
+                    .map(|(&(start, end), &notes)| MappingEntry { start, end, notes })
+                    .collect(),
+            }),
+        }
+    }
+}
+
+impl TryFrom<ScoreFile> for Score {
+    type Error = ProjectLoadError;
+
+    fn try_from(file: ScoreFile) -> Result<Self, Self::Error> {
+        if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&file.format_version) {
+            return Err(ProjectLoadError::UnsupportedVersion(file.format_version));
+        }
+        let mut bpms = OrdMap::new();
+        for BpmEntry { beat, bpm } in file.bpms {
+            if !(bpm.is_finite() && bpm > 0.0) {
+                return Err(ProjectLoadError::InvalidValue(format!(
+                    "BPM at beat {} must be positive, found {}",
+                    beat.0, bpm
+                )));
+            }
+            bpms.insert(BeatPosition(beat.0), Bpm(bpm));
+        }
+        let mut measure_lengths = OrdMap::new();
+        for MeasureLengthEntry { beat, length } in file.measure_lengths {
+            let Fraction(numerator, denominator) = length;
+            if numerator <= BigInt::zero() || denominator <= BigInt::zero() {
+                return Err(ProjectLoadError::InvalidValue(format!(
+                    "measure length at beat {} must be positive, found {}/{}",
+                    beat.0, numerator, denominator
+                )));
+            }
+            let length = MeasureLength {
+                numerator: BigIntData(numerator),
+                denominator: BigIntData(denominator),
+            };
+            measure_lengths.insert(BeatPosition(beat.0), length);
+        }
+        let tracks = file.tracks.into_iter().map(Track::from).collect();
+        Ok(Score {
+            tracks,
+            measure_lengths,
+            bpms,
+            offset: file.offset,
+            lyrics: file.lyrics,
+            font_file: file.font_file,
+            music: file.music.map(|music| MusicInfo {
+                path: music.path,
+                duration: music.duration,
+                sample_rate: music.sample_rate,
+                channels: music.channels,
+                hash: music.sha256,
+            }),
+        })
+    }
+}
+
+impl From<TrackEntry> for Track {
+    fn from(entry: TrackEntry) -> Self {
+        Self {
+            start_beat: BeatPosition(entry.start_beat.0),
+            elements: entry
+                .elements
+                .into_iter()
+                .map(|element| ScoreElement {
+                    kind: match element.kind {
+                        ElementKindEntry::Start => ScoreElementKind::Start,
+                        ElementKindEntry::Stop => ScoreElementKind::Stop,
+                        ElementKindEntry::Skip => ScoreElementKind::Skip,
+                    },
+                    length: BeatLength(element.length.0),
+                })
+                .collect(),
+            lyrics: entry.lyrics.map(|lyrics| Lyrics {
+                text: lyrics.text,
+                mappings: lyrics
+                    .mappings
+                    .into_iter()
+                    .map(|m| ((m.start, m.end), m.notes))
+                    .collect(),
+            }),
+        }
+    }
+}
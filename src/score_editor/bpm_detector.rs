@@ -1,4 +1,3 @@
-use druid::im::Vector;
 use druid::widget::Button;
 use druid::widget::Flex;
 use druid::widget::Label;
@@ -7,6 +6,7 @@ use druid::Data;
 use druid::Lens;
 use druid::Widget;
 use druid::WidgetExt;
+use im::Vector;
 
 use crate::linest::Linest;
 use crate::linest::LinestResult;
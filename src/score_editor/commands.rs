@@ -1,6 +1,13 @@
+use std::path::PathBuf;
+
+use crate::audio::AudioEvent;
 use crate::schema::BeatPosition;
 use crate::schema::Bpm;
 use crate::schema::MeasureLength;
+use crate::schema::MusicInfo;
+use crate::schema::MusicStatus;
+use druid::FileInfo;
+use druid::FileSpec;
 use druid::SingleUse;
 
 #[derive(Debug)]
@@ -17,3 +24,33 @@ pub struct SetBpmCommand {
 }
 
 selector! { pub EDIT_BPM_SELECTOR: SingleUse<SetBpmCommand> }
+
+pub const PROJECT_FILE_TYPE: FileSpec = FileSpec::new("Karaoke project", &["toml"]);
+
+selector! { pub OPEN_PROJECT_SELECTOR: FileInfo }
+selector! { pub SAVE_PROJECT_AS_SELECTOR: FileInfo }
+
+pub const ASS_FILE_TYPE: FileSpec = FileSpec::new("ASS subtitles", &["ass", "ssa"]);
+pub const AUDACITY_LABELS_FILE_TYPE: FileSpec = FileSpec::new("Audacity labels", &["txt"]);
+
+selector! { pub IMPORT_SELECTOR: FileInfo }
+selector! { pub EXPORT_TRACK_LABELS_SELECTOR: FileInfo }
+selector! { pub EXPORT_SYLLABLE_LABELS_SELECTOR: FileInfo }
+
+pub const FONT_FILE_TYPE: FileSpec = FileSpec::new("Fonts", &["ttf", "otf", "ttc"]);
+
+selector! { pub SET_FONT_FILE_SELECTOR: FileInfo }
+
+selector! { pub AUDIO_EVENT_SELECTOR: AudioEvent }
+
+pub struct MusicVerification {
+    pub path: PathBuf,
+    pub status: Result<MusicStatus, String>,
+    /// The complete metadata, if the music had only been probed and has now been measured
+    pub measured: Option<MusicInfo>,
+}
+
+selector! { pub MUSIC_VERIFIED_SELECTOR: MusicVerification }
+
+/// Shows an error in the status bar of the editor
+selector! { pub SHOW_STATUS_SELECTOR: String }
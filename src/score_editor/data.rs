@@ -1,3 +1,5 @@
+use std::path::PathBuf;
+
 use super::bpm_detector::BpmDetectorData;
 use crate::schema::BeatLength;
 use crate::schema::BeatPosition;
@@ -23,6 +25,8 @@ pub struct ScoreEditorData {
     pub music_volume: f64,
     #[new(value = "0.4")]
     pub metronome_volume: f64,
+    #[new(value = "1.0")]
+    pub playback_speed: f64,
     #[new(default)]
     pub bpm_detector_data: BpmDetectorData,
     #[new(default)]
@@ -31,6 +35,33 @@ pub struct ScoreEditorData {
 
     #[new(default)]
     pub music_playback_position: Option<MusicPlaybackPositionData>,
+    /// Playback repeats between these beats when both are set, in order
+    #[new(default)]
+    pub loop_start: Option<BeatPosition>,
+    #[new(default)]
+    pub loop_end: Option<BeatPosition>,
+
+    #[new(default)]
+    #[data(eq)]
+    pub project_path: Option<PathBuf>,
+    /// The last audio event or error, shown in the status bar
+    #[new(default)]
+    pub audio_status: String,
+    /// A problem with the music file of the project, shown in the status bar
+    #[new(default)]
+    pub music_warning: String,
+    /// The last other error, shown in the status bar
+    #[new(default)]
+    pub status_message: String,
+}
+
+impl ScoreEditorData {
+    pub fn loop_range(&self) -> Option<(&BeatPosition, &BeatPosition)> {
+        match (&self.loop_start, &self.loop_end) {
+            (Some(start), Some(end)) if start < end => Some((start, end)),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Data)]
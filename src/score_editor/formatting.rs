@@ -1,3 +1,4 @@
+use crate::formats::split_time;
 use crate::schema::BeatLength;
 use crate::schema::BeatPosition;
 use num::BigRational;
@@ -35,11 +36,6 @@ pub fn format_beat_position(pos: &BeatPosition) -> String {
 }
 
 pub fn format_time(time: f64) -> String {
-    let millis = ((time + 0.0005) * 1000.0) as u64;
-    format!(
-        "{}:{:02}.{:03}",
-        millis / 60000,
-        millis % 60000 / 1000,
-        millis % 1000
-    )
+    let (minutes, seconds, millis) = split_time(time, 1000);
+    format!("{}:{:02}.{:03}", minutes, seconds, millis)
 }
@@ -0,0 +1,125 @@
+use std::collections::VecDeque;
+
+pub const MAX_HISTORY_SIZE: usize = 500;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EditKind {
+    /// Appending or removing elements with the keyboard
+    Typing,
+    /// Edits made outside of the score editor, such as typing lyrics
+    Text,
+    Other,
+}
+
+/// Undo/redo stacks of snapshots.
+/// Consecutive edits of the same kind, except for `EditKind::Other`, are undone at once.
+pub struct History<T> {
+    undo_stack: VecDeque<T>,
+    redo_stack: Vec<T>,
+    max_size: usize,
+    last_kind: Option<EditKind>,
+}
+
+impl<T> History<T> {
+    pub fn new(max_size: usize) -> Self {
+        assert!(max_size > 0);
+        Self {
+            undo_stack: VecDeque::new(),
+            redo_stack: Vec::new(),
+            max_size,
+            last_kind: None,
+        }
+    }
+
+    /// Records `before`, the snapshot taken just before an edit of the given kind.
+    pub fn record(&mut self, before: T, kind: EditKind) {
+        self.redo_stack.clear();
+        if kind == EditKind::Other || self.last_kind != Some(kind) {
+            if self.undo_stack.len() == self.max_size {
+                self.undo_stack.pop_front();
+            }
+            self.undo_stack.push_back(before);
+        }
+        self.last_kind = Some(kind);
+    }
+
+    /// Prevents the next edit from being merged into the previous one.
+    pub fn break_group(&mut self) {
+        self.last_kind = None;
+    }
+
+    pub fn clear(&mut self) {
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+        self.last_kind = None;
+    }
+
+    pub fn undo(&mut self, current: T) -> Option<T> {
+        let ret = self.undo_stack.pop_back()?;
+        self.redo_stack.push(current);
+        self.last_kind = None;
+        Some(ret)
+    }
+
+    pub fn redo(&mut self, current: T) -> Option<T> {
+        let ret = self.redo_stack.pop()?;
+        self.undo_stack.push_back(current);
+        self.last_kind = None;
+        Some(ret)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::EditKind::*;
+    use super::History;
+
+    #[test]
+    fn test_undo_redo() {
+        let mut history = History::new(10);
+        history.record(0, Other);
+        history.record(1, Other);
+        assert_eq!(history.undo(2), Some(1));
+        assert_eq!(history.undo(1), Some(0));
+        assert_eq!(history.undo(0), None);
+        assert_eq!(history.redo(0), Some(1));
+        assert_eq!(history.redo(1), Some(2));
+        assert_eq!(history.redo(2), None);
+
+        history.undo(2);
+        history.record(1, Other);
+        assert_eq!(history.redo(3), None);
+    }
+
+    #[test]
+    fn test_grouping() {
+        let mut history = History::new(10);
+        history.record(0, Typing);
+        history.record(1, Typing);
+        history.record(2, Typing);
+        history.break_group();
+        history.record(3, Typing);
+        history.record(4, Text);
+        history.record(5, Text);
+        history.record(6, Other);
+        history.record(7, Other);
+        assert_eq!(history.undo(8), Some(7));
+        assert_eq!(history.undo(7), Some(6));
+        assert_eq!(history.undo(6), Some(4));
+        assert_eq!(history.undo(4), Some(3));
+        assert_eq!(history.undo(3), Some(0));
+        assert_eq!(history.undo(0), None);
+    }
+
+    #[test]
+    fn test_bounded() {
+        let mut history = History::new(3);
+        for i in 0..5 {
+            history.record(i, Other);
+        }
+        assert_eq!(history.undo(5), Some(4));
+        assert_eq!(history.undo(4), Some(3));
+        assert_eq!(history.undo(3), Some(2));
+        assert_eq!(history.undo(2), None);
+    }
+}
@@ -1,16 +1,26 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{
+    cell::RefCell,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
 
 use druid::{
+    commands::SHOW_OPEN_PANEL,
     keyboard_types::Key,
     kurbo::{Line, RoundedRect},
-    piet::{Image, InterpolationMode},
-    Color, Event, EventCtx, KeyEvent, Modifiers, Rect, RenderContext, Size, Widget,
+    piet::{Image, InterpolationMode, Text, TextLayoutBuilder},
+    widget::{Button, Flex, Label},
+    Color, Event, EventCtx, ExtEventSink, FileDialogOptions, KeyEvent, LifeCycle, Modifiers, Rect,
+    RenderContext, Size, Target, Widget, WidgetExt,
 };
 use itertools::Itertools;
 
-use super::ScoreEditorData;
+use super::{
+    commands::{FONT_FILE_TYPE, SET_FONT_FILE_SELECTOR, SHOW_STATUS_SELECTOR},
+    ScoreEditorData,
+};
 use crate::{
-    fonts::{render_text, FontLoader, RenderedText},
+    fonts::{render_text, FontLoader, ForceLoad, RenderTextError, RenderedText},
     linest::map_f64,
     schema::Lyrics,
 };
@@ -18,9 +28,12 @@ use crate::{
 #[derive(Default)]
 struct LyricsMappingEditor {
     font_loader: Rc<RefCell<FontLoader>>,
+    /// Glyph indices into the rendered text, converted to character indices for the mappings
     cursor_pos: usize,
     select_pos: usize,
-    text_cache: Option<(String, RenderedText)>,
+    text_cache: Option<(PathBuf, String, Result<RenderedText, RenderTextError>)>,
+    /// Reports rendering errors to the status bar of the editor
+    sink: Option<ExtEventSink>,
 }
 
 impl Widget<ScoreEditorData> for LyricsMappingEditor {
@@ -42,26 +55,47 @@ impl Widget<ScoreEditorData> for LyricsMappingEditor {
                 Key::ArrowDown => self.modify_mapping(ctx, Decrement, data),
                 _ => {}
             },
+            Event::Command(command) => {
+                if let Some(file_info) = command.get(SET_FONT_FILE_SELECTOR) {
+                    let path = file_info.path().to_owned();
+                    // Retry even if the file failed to load before, it may have been fixed since
+                    let _ = self
+                        .font_loader
+                        .borrow_mut()
+                        .get(path.clone(), ForceLoad::True);
+                    data.score.font_file = path;
+                    self.text_cache = None;
+                    self.cursor_pos = 0;
+                    self.select_pos = 0;
+                    ctx.request_paint();
+                }
+            }
             _ => {}
         }
     }
 
     fn lifecycle(
         &mut self,
-        _ctx: &mut druid::LifeCycleCtx,
-        _event: &druid::LifeCycle,
+        ctx: &mut druid::LifeCycleCtx,
+        event: &druid::LifeCycle,
         _data: &ScoreEditorData,
         _env: &druid::Env,
     ) {
+        if let LifeCycle::WidgetAdded = event {
+            self.sink = Some(ctx.get_external_handle());
+        }
     }
 
     fn update(
         &mut self,
-        _ctx: &mut druid::UpdateCtx,
-        _old_data: &ScoreEditorData,
-        _data: &ScoreEditorData,
+        ctx: &mut druid::UpdateCtx,
+        old_data: &ScoreEditorData,
+        data: &ScoreEditorData,
         _env: &druid::Env,
     ) {
+        if old_data.score.font_file != data.score.font_file {
+            ctx.request_paint();
+        }
     }
 
     fn layout(
@@ -84,23 +118,54 @@ impl Widget<ScoreEditorData> for LyricsMappingEditor {
             let Self {
                 text_cache,
                 font_loader,
+                sink,
                 ..
             } = self;
+            let font_file = &data.score.font_file;
+            let had_error = matches!(text_cache, Some((_, _, Err(_))));
             let text = match text_cache {
-                Some((text, cache)) if text == &lyrics.text => cache,
+                Some((path, text, cache)) if path == font_file && text == &lyrics.text => cache,
                 _ => {
+                    let text = render_text(
+                        (&*font_loader).borrow_mut(),
+                        font_file.clone(),
+                        ctx,
+                        &lyrics.text,
+                    );
+                    let message = match &text {
+                        Err(e) => Some(error_message(font_file, e)),
+                        // Clear the error once a font works
+                        Ok(_) if had_error => Some(String::new()),
+                        Ok(_) => None,
+                    };
+                    if let (Some(message), Some(sink)) = (message, sink) {
+                        let _ = sink.submit_command(SHOW_STATUS_SELECTOR, message, Target::Auto);
+                    }
                     &self
                         .text_cache
-                        .insert((
-                            lyrics.text.to_owned(),
-                            render_text(
-                                (&*font_loader).borrow_mut(),
-                                data.score.font_file.clone(),
-                                ctx,
-                                &lyrics.text,
-                            ),
-                        ))
-                        .1
+                        .insert((font_file.clone(), lyrics.text.to_owned(), text))
+                        .2
+                }
+            };
+            let text = match text {
+                Ok(text) => text,
+                Err(e) => {
+                    let message = format!(
+                        "{}\nChoose another font above.",
+                        error_message(font_file, e)
+                    );
+                    let max_width = ctx.size().width - 20.0;
+                    let layout = ctx
+                        .text()
+                        .new_text_layout(message)
+                        .max_width(max_width)
+                        .text_color(Color::rgb8(255, 96, 96))
+                        .build();
+                    // The error is in the status bar even if it can't be shown here
+                    if let Ok(layout) = layout {
+                        ctx.draw_text(&layout, (10.0, 30.0));
+                    }
+                    return;
                 }
             };
             // println!("{:?}", text.glyphs);
@@ -131,6 +196,8 @@ impl Widget<ScoreEditorData> for LyricsMappingEditor {
             let top_y = top_y + image_size.height + 10.0;
 
             for (&(s, t), &v) in &lyrics.mappings {
+                let s = text.char_to_glyph_index(&lyrics.text, s);
+                let t = text.char_to_glyph_index(&lyrics.text, t);
                 for (x1, x2) in (0..=v)
                     .map(|i| map_f64(i as f64 / v as f64, 0.0..1., get_x(s)..get_x(t)))
                     .tuple_windows()
@@ -143,6 +210,15 @@ impl Widget<ScoreEditorData> for LyricsMappingEditor {
     }
 }
 
+fn error_message(font_file: &Path, error: &RenderTextError) -> String {
+    match error {
+        RenderTextError::FontLoadError(e) => {
+            format!("Failed to load the font {}: {}", font_file.display(), e)
+        }
+        RenderTextError::ImageError(e) => format!("Failed to draw the lyrics: {}", e),
+    }
+}
+
 fn selected_lyrics(data: &ScoreEditorData) -> Option<&Lyrics> {
     if let Some(track_id) = data.selected_track {
         let track = &data.score.tracks[track_id];
@@ -170,7 +246,7 @@ use IncrementOrDecrement::*;
 
 impl LyricsMappingEditor {
     fn move_cursor(&mut self, ctx: &mut EventCtx, dir: IncrementOrDecrement, mods: &Modifiers) {
-        if let Some((_, text)) = &self.text_cache {
+        if let Some((_, _, Ok(text))) = &self.text_cache {
             let len = text.glyphs.len();
             let is_boundary = |&x: &usize| text.is_boundary(x);
             self.cursor_pos = match dir {
@@ -190,12 +266,19 @@ impl LyricsMappingEditor {
         dir: IncrementOrDecrement,
         data: &mut ScoreEditorData,
     ) {
-        if let (Some((_, text)), Some(lyrics)) = (&self.text_cache, selected_lyrics_mut(data)) {
+        if let (Some((_, _, Ok(text))), Some(lyrics)) =
+            (&self.text_cache, selected_lyrics_mut(data))
+        {
             let (s, t) = match (self.cursor_pos, self.select_pos) {
                 (s, t) if s < t => (s, t),
                 (s, t) if s > t => (t, s),
                 _ => return,
             };
+            if !text.is_boundary(s) || !text.is_boundary(t) {
+                return;
+            }
+            let s = text.glyph_to_char_index(&lyrics.text, s);
+            let t = text.glyph_to_char_index(&lyrics.text, t);
             // TODO naive implementation
             let mappings = &mut lyrics.mappings;
             if mappings
@@ -204,9 +287,6 @@ impl LyricsMappingEditor {
             {
                 return;
             }
-            if !text.is_boundary(s) || !text.is_boundary(t) {
-                return;
-            }
             match (mappings.get(&(s, t)).copied(), dir) {
                 (Some(1), Decrement) => {
                     mappings.remove(&(s, t));
@@ -227,10 +307,27 @@ impl LyricsMappingEditor {
 pub fn build_lyrics_mapping_dialog(
     font_loader: Rc<RefCell<FontLoader>>,
 ) -> impl Widget<ScoreEditorData> {
-    LyricsMappingEditor {
-        font_loader,
-        cursor_pos: 0,
-        select_pos: 0,
-        text_cache: None,
-    }
+    let font_picker = Flex::row()
+        .with_child(Button::new("Choose font...").on_click(|ctx, _, _| {
+            let options = FileDialogOptions::new()
+                .allowed_types(vec![FONT_FILE_TYPE])
+                .accept_command(SET_FONT_FILE_SELECTOR);
+            ctx.submit_command(SHOW_OPEN_PANEL.with(options));
+        }))
+        .with_spacer(10.0)
+        .with_child(Label::dynamic(|data: &ScoreEditorData, _| {
+            data.score.font_file.display().to_string()
+        }))
+        .padding(5.0);
+
+    Flex::column().with_child(font_picker).with_flex_child(
+        LyricsMappingEditor {
+            font_loader,
+            cursor_pos: 0,
+            select_pos: 0,
+            text_cache: None,
+            sink: None,
+        },
+        1.0,
+    )
 }
@@ -3,6 +3,7 @@ mod bpm_dialog;
 mod commands;
 mod data;
 mod formatting;
+mod history;
 mod layouts;
 mod lyrics_editor;
 mod lyrics_mapping_dialog;
@@ -12,11 +13,17 @@ mod score_editor_widget;
 
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::sync::mpsc;
+use std::thread;
 
+use crate::audio::AudioEvent;
 use crate::audio::AudioManager;
+use crate::config::PlaybackOptions;
 use crate::fonts::FontLoader;
 use crate::schema::BeatLength;
 use crate::schema::Score;
+use crate::time_stretch::MAX_SPEED;
+use crate::time_stretch::MIN_SPEED;
 use druid::text::ParseFormatter;
 use druid::widget::Flex;
 use druid::widget::Label;
@@ -24,21 +31,29 @@ use druid::widget::Scroll;
 use druid::widget::Slider;
 use druid::widget::Split;
 use druid::widget::TextBox;
+use druid::ExtEventSink;
 use druid::Insets;
+use druid::Target;
 use druid::Widget;
 use druid::WidgetExt;
 use druid::WidgetId;
+use itertools::Itertools;
 use num::BigRational;
 
+use self::commands::AUDIO_EVENT_SELECTOR;
 use self::formatting::beat_label_string;
 use self::formatting::format_time;
+use self::history::History;
+use self::history::MAX_HISTORY_SIZE;
 use self::lyrics_editor::lyrics_editor;
 use self::score_editor_widget::ScoreEditor;
 
 pub use self::data::ScoreEditorData;
 
+/// `audio_manager` is `None` if audio is unavailable, in which case the editor works without it.
 pub fn build_toplevel_widget(
-    audio_manager: AudioManager,
+    audio_manager: Option<AudioManager>,
+    playback_options: PlaybackOptions,
     font_loader: FontLoader,
 ) -> impl Widget<ScoreEditorData> {
     let status_bar = Flex::row()
@@ -79,15 +94,43 @@ pub fn build_toplevel_widget(
         .with_spacer(5.0)
         .with_child(Label::new("Metronome vol:"))
         .with_child(Slider::new().lens(ScoreEditorData::metronome_volume))
+        .with_spacer(5.0)
+        .with_child(
+            Label::dynamic(|speed: &f64, _| format!("Speed: {:.2}x", speed))
+                .fix_width(90.0)
+                .lens(ScoreEditorData::playback_speed),
+        )
+        .with_child(
+            Slider::new()
+                .with_range(MIN_SPEED, MAX_SPEED)
+                .lens(ScoreEditorData::playback_speed),
+        )
+        .with_spacer(20.0)
+        .with_child(Label::dynamic(|data: &ScoreEditorData, _| {
+            [
+                &data.music_warning,
+                &data.status_message,
+                &data.audio_status,
+            ]
+            .iter()
+            .filter(|s| !s.is_empty())
+            .join("  ")
+        }))
         .main_axis_alignment(druid::widget::MainAxisAlignment::Start)
         .must_fill_main_axis(true)
         .padding(5.0);
 
     let score_editor = ScoreEditor {
         audio_manager,
+        playback_options,
         font_loader: Rc::new(RefCell::new(font_loader)),
         layout_cache: Vec::new(),
         hover_cursor: None,
+        history: History::new(MAX_HISTORY_SIZE),
+        pending_edit: None,
+        restoring_history: false,
+        sink: None,
+        paint_error: None,
     };
 
     let widget_id = WidgetId::next();
@@ -109,3 +152,17 @@ pub fn build_toplevel_widget(
         .split_point(0.8)
         .draggable(true)
 }
+
+/// Delivers the events of the `AudioManager` to the score editor until the app exits.
+pub fn forward_audio_events(receiver: mpsc::Receiver<AudioEvent>, sink: ExtEventSink) {
+    thread::spawn(move || {
+        for event in receiver {
+            if sink
+                .submit_command(AUDIO_EVENT_SELECTOR, event, Target::Auto)
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+}
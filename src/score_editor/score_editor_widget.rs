@@ -2,26 +2,44 @@ use std::cell::RefCell;
 use std::cmp::Reverse;
 use std::collections::binary_heap::PeekMut;
 use std::collections::BinaryHeap;
+use std::fs;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::Write;
 use std::ops::Range;
+use std::path::Path;
 use std::rc::Rc;
-use std::sync::mpsc;
+use std::thread;
 
+use crate::audio::measure_music;
 use crate::audio::AudioCommand;
+use crate::audio::AudioEvent;
 use crate::audio::AudioManager;
-use crate::audio::SoundEffectSchedule;
+use crate::audio::LoopRegion;
+use crate::config::PlaybackOptions;
 use crate::fonts::FontLoader;
-use crate::schema::Lyrics;
-use crate::schema::iterate_beat_times;
+use crate::formats::ass::read_ass;
+use crate::formats::audacity::read_audacity_labels;
+use crate::formats::audacity::write_audacity_labels;
+use crate::formats::audacity::LabelKind;
+use crate::metronome::metronome_schedule_list;
+use crate::metronome::metronome_schedules;
+use crate::schema::bar_line_before;
 use crate::schema::BeatLength;
 use crate::schema::BeatPosition;
 use crate::schema::Bpm;
+use crate::schema::Lyrics;
 use crate::schema::MeasureLength;
+use crate::schema::MusicStatus;
+use crate::schema::Score;
 use crate::schema::ScoreElementKind;
 use crate::schema::Track;
-use druid::im::OrdMap;
-use druid::im::Vector;
+use anyhow::anyhow;
+use druid::commands::SHOW_OPEN_PANEL;
+use druid::commands::SHOW_SAVE_PANEL;
 use druid::keyboard_types::Key;
 use druid::kurbo::Line;
+use druid::piet;
 use druid::piet::IntoBrush;
 use druid::piet::Piet;
 use druid::piet::Text;
@@ -32,6 +50,9 @@ use druid::Data;
 use druid::Env;
 use druid::Event;
 use druid::EventCtx;
+use druid::ExtEventSink;
+use druid::FileDialogOptions;
+use druid::FileInfo;
 use druid::Insets;
 use druid::KeyEvent;
 use druid::LifeCycle;
@@ -40,11 +61,16 @@ use druid::MouseEvent;
 use druid::PaintCtx;
 use druid::Rect;
 use druid::RenderContext;
+use druid::Selector;
 use druid::SingleUse;
 use druid::Size;
+use druid::Target;
 use druid::Widget;
 use druid::WidgetExt;
+use druid::WidgetId;
 use druid::WindowDesc;
+use im::OrdMap;
+use im::Vector;
 use itertools::iterate;
 use itertools::Itertools;
 use num::BigRational;
@@ -52,10 +78,24 @@ use num::ToPrimitive;
 
 use super::bpm_detector::build_bpm_detector_widget;
 use super::bpm_dialog::build_bpm_dialog;
+use super::commands::MusicVerification;
+use super::commands::ASS_FILE_TYPE;
+use super::commands::AUDACITY_LABELS_FILE_TYPE;
+use super::commands::AUDIO_EVENT_SELECTOR;
 use super::commands::EDIT_BPM_SELECTOR;
 use super::commands::EDIT_MEAUSRE_LENGTH_SELECTOR;
+use super::commands::EXPORT_SYLLABLE_LABELS_SELECTOR;
+use super::commands::EXPORT_TRACK_LABELS_SELECTOR;
+use super::commands::IMPORT_SELECTOR;
+use super::commands::MUSIC_VERIFIED_SELECTOR;
+use super::commands::OPEN_PROJECT_SELECTOR;
+use super::commands::PROJECT_FILE_TYPE;
+use super::commands::SAVE_PROJECT_AS_SELECTOR;
+use super::commands::SHOW_STATUS_SELECTOR;
 use super::data::MusicPlaybackPositionData;
 use super::data::ScoreEditorData;
+use super::history::EditKind;
+use super::history::History;
 use super::layouts::*;
 use super::lyrics_editor::SET_LYRICS_RANGE;
 use super::lyrics_editor::UPDATE_SELECTION_SELECTOR;
@@ -66,10 +106,21 @@ use super::misc::cursor_delta_candidates;
 use super::misc::split_into_rows;
 
 pub struct ScoreEditor {
-    pub(super) audio_manager: AudioManager,
+    /// `None` when audio is unavailable or has been lost
+    pub(super) audio_manager: Option<AudioManager>,
+    pub(super) playback_options: PlaybackOptions,
     pub font_loader: Rc<RefCell<FontLoader>>,
     pub(super) layout_cache: Vec<ScoreRow>,
     pub(super) hover_cursor: Option<BeatPosition>,
+    pub(super) history: History<Score>,
+    /// The kind of the edit being made by the event currently handled
+    pub(super) pending_edit: Option<EditKind>,
+    /// Set when the score is replaced by undo, redo or loading, which must not be recorded
+    pub(super) restoring_history: bool,
+    /// Reports errors from `paint`, which cannot submit commands itself
+    pub(super) sink: Option<ExtEventSink>,
+    /// The error shown for the last paint, so that it is reported once
+    pub(super) paint_error: Option<String>,
 }
 
 pub struct ScoreRow {
@@ -119,10 +170,52 @@ pub struct TrackView {
 
 impl Widget<ScoreEditorData> for ScoreEditor {
     fn event(&mut self, ctx: &mut EventCtx, event: &Event, data: &mut ScoreEditorData, _env: &Env) {
+        self.pending_edit = match event {
+            Event::KeyDown(KeyEvent { key, mods, .. }) if is_typing(key, mods) => {
+                Some(EditKind::Typing)
+            }
+            Event::KeyDown(..) | Event::MouseDown(..) => {
+                self.history.break_group();
+                Some(EditKind::Other)
+            }
+            Event::Command(..) => Some(EditKind::Other),
+            _ => None,
+        };
         match event {
             Event::WindowConnected => {
                 ctx.request_focus();
             }
+            Event::KeyDown(KeyEvent { key, mods, .. }) if mods.contains(Modifiers::CONTROL) => {
+                if let Key::Character(s) = key {
+                    match s.as_str() {
+                        "s" | "S" => {
+                            if mods.contains(Modifiers::SHIFT) {
+                                self.show_save_project_panel(ctx);
+                            } else {
+                                self.save_project(ctx, data);
+                            }
+                        }
+                        "o" | "O" => self.show_open_project_panel(ctx),
+                        "i" | "I" => self.show_import_panel(ctx),
+                        "e" | "E" => {
+                            if mods.contains(Modifiers::SHIFT) {
+                                self.show_export_labels_panel(ctx, EXPORT_SYLLABLE_LABELS_SELECTOR);
+                            } else {
+                                self.show_export_labels_panel(ctx, EXPORT_TRACK_LABELS_SELECTOR);
+                            }
+                        }
+                        "z" | "Z" => {
+                            if mods.contains(Modifiers::SHIFT) {
+                                self.redo(data);
+                            } else {
+                                self.undo(data);
+                            }
+                        }
+                        "y" | "Y" => self.redo(data),
+                        _ => {}
+                    }
+                }
+            }
             Event::KeyDown(KeyEvent { key, mods, .. }) => match key {
                 Key::Character(s) => match s.as_str() {
                     "1" => {
@@ -133,7 +226,7 @@ impl Widget<ScoreEditorData> for ScoreEditor {
                     }
                     " " => {
                         if mods.contains(Modifiers::SHIFT) {
-                            self.toggle_music_play(ctx, data).unwrap();
+                            self.toggle_music_play(ctx, data);
                         } else {
                             append_element(data, ScoreElementKind::Skip);
                         }
@@ -176,10 +269,12 @@ impl Widget<ScoreEditorData> for ScoreEditor {
                     "b" => self.edit_bpm(ctx, data),
                     "B" => self.open_bpm_detector(ctx),
                     "/" => {
-                        if let Some(time) = self.audio_manager.playback_position() {
+                        if let Some(time) = self.playback_position() {
                             data.bpm_detector_data.push(time);
                         }
                     }
+                    "[" => toggle_loop_point(&mut data.loop_start, &data.cursor_position),
+                    "]" => toggle_loop_point(&mut data.loop_end, &data.cursor_position),
                     "l" => self.edit_lyrics_mapping(ctx, data),
                     "L" => {
                         // Remove lyrics
@@ -257,6 +352,65 @@ impl Widget<ScoreEditorData> for ScoreEditor {
                         Some(bpm) => data.score.bpms.insert(command.position, bpm),
                         None => data.score.bpms.remove(&command.position),
                     };
+                } else if let Some(file_info) = command.get(OPEN_PROJECT_SELECTOR) {
+                    self.open_project(ctx, data, file_info.path());
+                } else if let Some(file_info) = command.get(IMPORT_SELECTOR) {
+                    let path = file_info.path();
+                    let message = match import_tracks(&data.score, &data.cursor_delta, path) {
+                        Ok(tracks) => {
+                            let message =
+                                format!("Imported {} tracks from {}", tracks.len(), path.display());
+                            data.score.tracks.extend(tracks);
+                            message
+                        }
+                        Err(e) => format!("Failed to import {}: {}", path.display(), e),
+                    };
+                    show_status(ctx, message);
+                } else if let Some(file_info) = command.get(EXPORT_TRACK_LABELS_SELECTOR) {
+                    export_labels(ctx, &data.score, LabelKind::Tracks, file_info.path());
+                } else if let Some(file_info) = command.get(EXPORT_SYLLABLE_LABELS_SELECTOR) {
+                    export_labels(ctx, &data.score, LabelKind::Syllables, file_info.path());
+                } else if let Some(file_info) = command.get(SAVE_PROJECT_AS_SELECTOR) {
+                    let path = file_info.path().to_owned();
+                    match data.score.save(&path) {
+                        Ok(()) => {
+                            show_status(ctx, format!("Saved {}", path.display()));
+                            data.project_path = Some(path);
+                        }
+                        Err(e) => {
+                            show_status(ctx, format!("Failed to save {}: {}", path.display(), e))
+                        }
+                    }
+                } else if let Some(event) = command.get(AUDIO_EVENT_SELECTOR) {
+                    if let AudioEvent::DeviceLost | AudioEvent::Stopped = event {
+                        self.audio_manager = None;
+                        data.playing_music = false;
+                        data.music_playback_position = None;
+                    }
+                    data.audio_status = event.to_string();
+                } else if let Some(verification) = command.get(MUSIC_VERIFIED_SELECTOR) {
+                    let path = &verification.path;
+                    // Ignore the result for the music of a project that has been closed
+                    if data.score.music.as_ref().map(|music| &music.path) == Some(path) {
+                        if let Some(measured) = &verification.measured {
+                            data.score.music = Some(measured.clone());
+                            // Filling in the metadata is not an edit to undo
+                            self.restoring_history = true;
+                        }
+                        data.music_warning = match &verification.status {
+                            Ok(MusicStatus::Unchanged) => String::new(),
+                            Ok(MusicStatus::Changed) => format!(
+                                "Warning: {} has changed since the timing was authored",
+                                path.display()
+                            ),
+                            Ok(MusicStatus::Missing) => {
+                                format!("Warning: {} does not exist", path.display())
+                            }
+                            Err(e) => format!("Failed to verify {}: {}", path.display(), e),
+                        };
+                    }
+                } else if let Some(message) = command.get(SHOW_STATUS_SELECTOR) {
+                    data.status_message = message.clone();
                 } else if let Some(selection) = command.get(UPDATE_SELECTION_SELECTOR) {
                     data.selection = selection.to_owned();
                 } else if let Some(()) = command.get(SET_LYRICS_RANGE) {
@@ -274,7 +428,7 @@ impl Widget<ScoreEditorData> for ScoreEditor {
             }
             Event::AnimFrame(..) => {
                 if data.playing_music {
-                    if let Some(time) = self.audio_manager.playback_position() {
+                    if let Some(time) = self.playback_position() {
                         if let Some(beat) = BigRational::from_float(data.score.time_to_beat(time)) {
                             let pos = MusicPlaybackPositionData {
                                 time,
@@ -292,13 +446,15 @@ impl Widget<ScoreEditorData> for ScoreEditor {
 
     fn lifecycle(
         &mut self,
-        _ctx: &mut druid::LifeCycleCtx,
+        ctx: &mut druid::LifeCycleCtx,
         event: &LifeCycle,
         data: &ScoreEditorData,
         _env: &Env,
     ) {
         if let LifeCycle::WidgetAdded = event {
-            self.send_volume(data);
+            self.sink = Some(ctx.get_external_handle());
+            self.send_playback_settings(data);
+            self.load_music(ctx.get_external_handle(), ctx.widget_id(), &data.score);
         }
     }
 
@@ -309,6 +465,10 @@ impl Widget<ScoreEditorData> for ScoreEditor {
         data: &ScoreEditorData,
         _env: &druid::Env,
     ) {
+        let edit_kind = self.pending_edit.take().unwrap_or(EditKind::Text);
+        if !old_data.score.same(&data.score) && !std::mem::take(&mut self.restoring_history) {
+            self.history.record(old_data.score.clone(), edit_kind);
+        }
         if !old_data.same(data) {
             self.hover_cursor = None;
             ctx.request_layout();
@@ -317,8 +477,16 @@ impl Widget<ScoreEditorData> for ScoreEditor {
         #[allow(clippy::float_cmp)]
         if old_data.music_volume != data.music_volume
             || old_data.metronome_volume != data.metronome_volume
+            || old_data.playback_speed != data.playback_speed
         {
-            self.send_volume(data);
+            self.send_playback_settings(data);
+        }
+        if old_data.loop_start != data.loop_start
+            || old_data.loop_end != data.loop_end
+            || (data.loop_range().is_some() && !old_data.score.same(&data.score))
+        {
+            // The times of the loop change with the tempo
+            self.send_loop(data);
         }
     }
 
@@ -429,10 +597,26 @@ impl Widget<ScoreEditorData> for ScoreEditor {
 
         let mut measure_lengths = data.score.measure_lengths.iter().peekable();
         let mut bpms = data.score.bpms.iter().peekable();
+        let mut error = None;
 
         for row in self.layout_cache.iter() {
             let get_x = |pos: &BeatPosition| get_x(row.beat_delta(pos));
 
+            // Draw loop region
+            if let Some((start, end)) = data.loop_range() {
+                let start = start.max(&row.beat_start);
+                let end = end.min(&row.beat_end);
+                if start < end {
+                    let rect = Rect::new(get_x(start), row.y, get_x(end), row.y + LINE_HEIGHT);
+                    ctx.fill(rect, &Color::rgba8(255, 165, 0, 0x40));
+                }
+            }
+            for beat in data.loop_start.iter().chain(&data.loop_end) {
+                if row.contains_beat(beat) {
+                    draw_cursor(ctx, get_x, beat, row.y, &Color::rgb8(255, 165, 0), 2.0);
+                }
+            }
+
             // Draw bar lines at the first of each measure
             for beat in row.bar_lines.iter() {
                 let x = get_x(beat);
@@ -472,7 +656,7 @@ impl Widget<ScoreEditorData> for ScoreEditor {
             // Draw tracks
             for track_view in row.tracks.iter() {
                 let track = &data.score.tracks[track_view.index];
-                draw_track(
+                let result = draw_track(
                     ctx,
                     get_x,
                     row,
@@ -481,6 +665,9 @@ impl Widget<ScoreEditorData> for ScoreEditor {
                     data.selected_track.map_or(false, |j| track_view.index == j),
                     &draw_rect,
                 );
+                if let Err(e) = result {
+                    error.get_or_insert(e);
+                }
             }
 
             // draw measure labels
@@ -493,7 +680,9 @@ impl Widget<ScoreEditorData> for ScoreEditor {
                     .build();
                 match layout {
                     Ok(layout) => ctx.draw_text(&layout, (get_x(beat), row.y)),
-                    Err(e) => eprintln!("{}", e),
+                    Err(e) => {
+                        error.get_or_insert(e);
+                    }
                 }
             }
 
@@ -507,23 +696,116 @@ impl Widget<ScoreEditorData> for ScoreEditor {
                     .build();
                 match layout {
                     Ok(layout) => ctx.draw_text(&layout, (get_x(beat), row.y)),
-                    Err(e) => eprintln!("{}", e),
+                    Err(e) => {
+                        error.get_or_insert(e);
+                    }
                 }
             }
         }
+        self.report_paint_error(error.map(|e| e.to_string()));
     }
 }
 
 impl ScoreEditor {
-    fn send_volume(&self, data: &ScoreEditorData) {
-        self.audio_manager
-            .command_sender()
-            .send(AudioCommand::SetVolume(data.music_volume))
-            .unwrap();
+    /// Does nothing without audio. A stopped audio thread is reported as an `AudioEvent`.
+    fn send_audio_command(&self, command: AudioCommand) {
+        if let Some(audio_manager) = &self.audio_manager {
+            audio_manager.send(command);
+        }
+    }
+
+    /// Shows a text layout error in the status bar when it first occurs, and clears it once
+    /// painting succeeds again.
+    fn report_paint_error(&mut self, error: Option<String>) {
+        if error == self.paint_error {
+            return;
+        }
+        let message = match &error {
+            Some(e) => format!("Failed to lay out text: {}", e),
+            None => String::new(),
+        };
+        if let Some(sink) = &self.sink {
+            let _ = sink.submit_command(SHOW_STATUS_SELECTOR, message, Target::Auto);
+        }
+        self.paint_error = error;
+    }
+
+    fn playback_position(&self) -> Option<f64> {
         self.audio_manager
-            .command_sender()
-            .send(AudioCommand::SetSoundEffectVolume(data.metronome_volume))
-            .unwrap();
+            .as_ref()
+            .and_then(AudioManager::playback_position)
+    }
+
+    fn send_playback_settings(&self, data: &ScoreEditorData) {
+        self.send_audio_command(AudioCommand::SetVolume(data.music_volume));
+        self.send_audio_command(AudioCommand::SetSoundEffectVolume(data.metronome_volume));
+        self.send_audio_command(AudioCommand::SetPlaybackSpeed(data.playback_speed));
+    }
+
+    fn send_loop(&self, data: &ScoreEditorData) {
+        let region = data.loop_range().map(|(start, end)| {
+            let end = data.score.beat_to_time(end);
+            LoopRegion {
+                start: data.score.beat_to_time(start),
+                end,
+                schedules: metronome_schedule_list(&data.score, start.clone(), end),
+            }
+        });
+        self.send_audio_command(AudioCommand::SetLoop(region));
+    }
+
+    fn undo(&mut self, data: &mut ScoreEditorData) {
+        if let Some(score) = self.history.undo(data.score.clone()) {
+            self.restore_score(data, score);
+        }
+    }
+
+    fn redo(&mut self, data: &mut ScoreEditorData) {
+        if let Some(score) = self.history.redo(data.score.clone()) {
+            self.restore_score(data, score);
+        }
+    }
+
+    fn restore_score(&mut self, data: &mut ScoreEditorData, score: Score) {
+        data.score = score;
+        data.selected_track = data.selected_track.filter(|&i| i < data.score.tracks.len());
+        self.restoring_history = true;
+    }
+
+    /// Starts loading the music, and verifies it on another thread, or measures it if it has only
+    /// been probed. The result is reported to `widget_id` by `MUSIC_VERIFIED_SELECTOR`.
+    fn load_music(&self, sink: ExtEventSink, widget_id: WidgetId, score: &Score) {
+        let music = match &score.music {
+            Some(music) => music.clone(),
+            None => return,
+        };
+        if let (Some(audio_manager), true) = (&self.audio_manager, music.path.exists()) {
+            audio_manager.load_music(music.path.clone());
+        }
+        thread::spawn(move || {
+            let verification = if music.hash.is_empty() && music.path.exists() {
+                let mut measured = music.clone();
+                match measure_music(&mut measured) {
+                    Ok(()) => MusicVerification {
+                        path: music.path,
+                        status: Ok(MusicStatus::Unchanged),
+                        measured: Some(measured),
+                    },
+                    Err(e) => MusicVerification {
+                        path: music.path,
+                        status: Err(e.to_string()),
+                        measured: None,
+                    },
+                }
+            } else {
+                MusicVerification {
+                    status: music.verify().map_err(|e| e.to_string()),
+                    path: music.path,
+                    measured: None,
+                }
+            };
+            let _ = sink.submit_command(MUSIC_VERIFIED_SELECTOR, verification, widget_id);
+        });
     }
 
     fn edit_measure_length(&self, ctx: &mut EventCtx, data: &ScoreEditorData) {
@@ -569,42 +851,91 @@ impl ScoreEditor {
         ctx.new_window(window_desc);
     }
 
+    fn save_project(&self, ctx: &mut EventCtx, data: &ScoreEditorData) {
+        match &data.project_path {
+            Some(path) => {
+                let message = match data.score.save(path) {
+                    Ok(()) => format!("Saved {}", path.display()),
+                    Err(e) => format!("Failed to save {}: {}", path.display(), e),
+                };
+                show_status(ctx, message);
+            }
+            None => self.show_save_project_panel(ctx),
+        }
+    }
+
+    fn show_save_project_panel(&self, ctx: &mut EventCtx) {
+        let options = FileDialogOptions::new()
+            .allowed_types(vec![PROJECT_FILE_TYPE])
+            .accept_command(SAVE_PROJECT_AS_SELECTOR);
+        ctx.submit_command(SHOW_SAVE_PANEL.with(options));
+    }
+
+    fn show_open_project_panel(&self, ctx: &mut EventCtx) {
+        let options = FileDialogOptions::new()
+            .allowed_types(vec![PROJECT_FILE_TYPE])
+            .accept_command(OPEN_PROJECT_SELECTOR);
+        ctx.submit_command(SHOW_OPEN_PANEL.with(options));
+    }
+
+    fn show_import_panel(&self, ctx: &mut EventCtx) {
+        let options = FileDialogOptions::new()
+            .allowed_types(vec![ASS_FILE_TYPE, AUDACITY_LABELS_FILE_TYPE])
+            .accept_command(IMPORT_SELECTOR);
+        ctx.submit_command(SHOW_OPEN_PANEL.with(options));
+    }
+
+    fn show_export_labels_panel(&self, ctx: &mut EventCtx, selector: Selector<FileInfo>) {
+        let options = FileDialogOptions::new()
+            .allowed_types(vec![AUDACITY_LABELS_FILE_TYPE])
+            .accept_command(selector);
+        ctx.submit_command(SHOW_SAVE_PANEL.with(options));
+    }
+
+    fn open_project(&mut self, ctx: &mut EventCtx, data: &mut ScoreEditorData, path: &Path) {
+        match Score::load(path) {
+            Ok(score) => {
+                self.load_music(ctx.get_external_handle(), ctx.widget_id(), &score);
+                data.music_warning.clear();
+                self.history.clear();
+                self.restoring_history = true;
+                data.score = score;
+                data.project_path = Some(path.to_owned());
+                data.selected_track = None;
+                data.cursor_position = BeatPosition::zero();
+                show_status(ctx, format!("Opened {}", path.display()));
+            }
+            Err(e) => show_status(ctx, format!("Failed to open {}: {}", path.display(), e)),
+        }
+    }
+
     fn open_bpm_detector(&self, ctx: &mut EventCtx) {
         let window_desc =
             WindowDesc::new(build_bpm_detector_widget().lens(ScoreEditorData::bpm_detector_data));
         ctx.new_window(window_desc)
     }
 
-    fn toggle_music_play(
-        &self,
-        ctx: &mut EventCtx,
-        data: &mut ScoreEditorData,
-    ) -> Result<(), mpsc::SendError<impl std::any::Any>> {
-        let sender = self.audio_manager.command_sender();
+    fn toggle_music_play(&self, ctx: &mut EventCtx, data: &mut ScoreEditorData) {
         if data.playing_music {
-            sender.send(AudioCommand::Pause)?;
+            self.send_audio_command(AudioCommand::Pause);
             data.playing_music = false;
             data.music_playback_position = None;
-        } else {
-            let pos = data.score.beat_to_time(&data.cursor_position);
-            sender.send(AudioCommand::Seek(pos))?;
-            sender.send(AudioCommand::SetSoundEffectSchedules(Box::new(
-                iterate_beat_times(
-                    data.score.offset,
-                    data.score.measure_lengths.clone(),
-                    data.score.bpms.clone(),
-                    data.cursor_position.clone(),
-                )
-                .map(|(first, time)| SoundEffectSchedule {
-                    time,
-                    frequency: if first { 1244.51 } else { 739.99 },
-                }),
-            )))?;
-            sender.send(AudioCommand::Play)?;
+        } else if self.audio_manager.is_some() {
+            let start = bar_line_before(
+                &data.score.measure_lengths,
+                &data.cursor_position,
+                self.playback_options.pre_roll_measures,
+            );
+            let pos = data.score.beat_to_time(&start);
+            self.send_audio_command(AudioCommand::Seek(pos));
+            self.send_audio_command(AudioCommand::SetSoundEffectSchedules(metronome_schedules(
+                &data.score,
+                start,
+            )));
+            self.send_audio_command(AudioCommand::Play);
             data.playing_music = true;
             ctx.request_anim_frame();
         }
-        Ok(())
     }
 
     fn handle_mouse_move(
@@ -622,6 +953,20 @@ impl ScoreEditor {
     }
 }
 
+/// Whether the key appends or removes an element, which are grouped into one undo step
+fn is_typing(key: &Key, mods: &Modifiers) -> bool {
+    if mods.contains(Modifiers::CONTROL) {
+        return false;
+    }
+    match key {
+        Key::Character(s) => {
+            matches!(s.as_str(), "1" | "2" | " ") && !mods.contains(Modifiers::SHIFT)
+        }
+        Key::Backspace => true,
+        _ => false,
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 fn draw_track(
     ctx: &mut PaintCtx,
@@ -631,7 +976,7 @@ fn draw_track(
     track: &Track,
     selected: bool,
     draw_rect: &Rect,
-) {
+) -> Result<(), piet::Error> {
     let track_end_beat = track.end_beat();
     let is_first = &row.beat_start <= track.start_beat();
     let is_final = track_end_beat <= row.beat_end;
@@ -642,6 +987,7 @@ fn draw_track(
         0.
     };
 
+    let mut result = Ok(());
     ctx.with_save(|ctx| {
         let rect = Rect::new(
             draw_rect.min_x(),
@@ -702,10 +1048,38 @@ fn draw_track(
                 .build();
             match layout {
                 Ok(layout) => ctx.draw_text(&layout, (min_x, note_rect.max_y())),
-                Err(e) => eprintln!("{}", e),
+                Err(e) => result = Err(e),
             };
         }
     });
+    result
+}
+
+/// Writes an Audacity label track, reporting the result in the status bar.
+fn export_labels(ctx: &mut EventCtx, score: &Score, kind: LabelKind, path: &Path) {
+    let result = File::create(path).and_then(|file| {
+        let mut out = BufWriter::new(file);
+        write_audacity_labels(score, kind, &mut out)?;
+        out.flush()
+    });
+    let message = match result {
+        Ok(()) => format!("Exported labels to {}", path.display()),
+        Err(e) => format!("Failed to export {}: {}", path.display(), e),
+    };
+    show_status(ctx, message);
+}
+
+/// Replaces the message in the status bar of the editor.
+fn show_status(ctx: &mut EventCtx, message: String) {
+    ctx.submit_command(SHOW_STATUS_SELECTOR.with(message).to(ctx.widget_id()));
+}
+
+/// Sets the loop point to the cursor, or clears it if it is already there.
+fn toggle_loop_point(point: &mut Option<BeatPosition>, cursor_position: &BeatPosition) {
+    *point = match point {
+        Some(current) if current == cursor_position => None,
+        _ => Some(cursor_position.clone()),
+    };
 }
 
 fn draw_cursor<'c>(
@@ -720,3 +1094,20 @@ fn draw_cursor<'c>(
     let line = Line::new((x, min_y), (x, min_y + LINE_HEIGHT));
     ctx.stroke(line, brush, width);
 }
+
+/// Reads tracks from a file made by another tool, quantizing times to `grid`.
+fn import_tracks(score: &Score, grid: &BeatLength, path: &Path) -> anyhow::Result<Vec<Track>> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("ass") | Some("ssa") => Ok(read_ass(score, &fs::read_to_string(path)?, grid)?),
+        Some("txt") => Ok(read_audacity_labels(
+            score,
+            &fs::read_to_string(path)?,
+            grid,
+        )?),
+        _ => Err(anyhow!("unsupported file type")),
+    }
+}
@@ -0,0 +1,411 @@
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+
+pub const MIN_SPEED: f64 = 0.25;
+pub const MAX_SPEED: f64 = 2.0;
+
+/// Step in frames of the coarse alignment search, which is then refined around the best offset
+const COARSE_STEP: usize = 8;
+
+/// Plays interleaved samples at a different speed without changing the pitch, by WSOLA
+/// (waveform similarity overlap-add).
+///
+/// Every step crossfades the input that naturally follows the previous segment into a new segment
+/// taken around the position the speed has advanced to, shifted by up to `tolerance` frames so
+/// that the two waveforms line up. At speed 1 the segments are contiguous and the input is
+/// reproduced as is, except that the output fades in after a reset.
+///
+/// This runs in the audio callback, so the alignment is searched on every `COARSE_STEP`-th frame
+/// first, and buffers are reused instead of allocated.
+pub struct TimeStretch<I> {
+    source: I,
+    channels: usize,
+    speed: f64,
+    /// Frames output per step, half of the window
+    hop: usize,
+    tolerance: usize,
+    /// Fade-in weights of the new segment over a step
+    fade_in: Vec<f32>,
+
+    /// Interleaved input from the frame `input_start`
+    input: VecDeque<f32>,
+    input_start: usize,
+    /// Frames of silence to read before the source
+    silence: usize,
+    source_exhausted: bool,
+    /// Input frame the next segment starts at before alignment
+    position: f64,
+    /// The input following the last segment, faded out in the next step
+    tail: Vec<f32>,
+    /// Where `reset_with_crossfade` builds the next tail
+    crossfade: Vec<f32>,
+    /// Mono mixes of the tail and of the input around the next segment, for the alignment
+    mono_tail: Vec<f32>,
+    mono_input: Vec<f32>,
+
+    output: Vec<f32>,
+    output_pos: usize,
+}
+
+impl<I> TimeStretch<I>
+where
+    I: Iterator<Item = f32>,
+{
+    pub fn new(source: I, channels: u16, sample_rate: u32) -> Self {
+        let channels = channels.max(1) as usize;
+        // 40ms windows, aligned within 10ms
+        let hop = (sample_rate as usize / 50).max(1);
+        let fade_in = (0..hop)
+            .map(|i| 0.5 - 0.5 * (PI * (i as f32 + 0.5) / hop as f32).cos())
+            .collect();
+        Self {
+            source,
+            channels,
+            speed: 1.0,
+            hop,
+            tolerance: hop / 2,
+            fade_in,
+
+            input: VecDeque::new(),
+            input_start: 0,
+            silence: 0,
+            source_exhausted: false,
+            position: 0.0,
+            tail: vec![0.0; hop * channels],
+            crossfade: Vec::with_capacity(hop * channels),
+            mono_tail: Vec::with_capacity(hop),
+            mono_input: Vec::with_capacity(hop * 2),
+
+            output: Vec::new(),
+            output_pos: 0,
+        }
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    /// Clamped between `MIN_SPEED` and `MAX_SPEED`.
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+    }
+
+    /// The source, e.g. to seek it. Call `reset` after moving it.
+    pub fn get_mut(&mut self) -> &mut I {
+        &mut self.source
+    }
+
+    /// Forgets the input read so far, to start over from the current position of the source after
+    /// `silence` frames of silence.
+    pub fn reset(&mut self, silence: usize) {
+        self.input.clear();
+        self.input_start = 0;
+        self.silence = silence;
+        self.source_exhausted = false;
+        self.position = 0.0;
+        self.tail.iter_mut().for_each(|x| *x = 0.0);
+        self.output.clear();
+        self.output_pos = 0;
+    }
+
+    /// Like `reset`, but crossfades from the output that would have followed instead of fading in
+    /// from silence, e.g. to jump back to the start of a loop without a click.
+    pub fn reset_with_crossfade(&mut self, silence: usize) {
+        let remaining = self.output.len() - self.output_pos;
+        self.crossfade.clear();
+        self.crossfade
+            .extend_from_slice(&self.output[self.output_pos..]);
+        self.crossfade
+            .extend_from_slice(&self.tail[..self.tail.len() - remaining]);
+        self.reset(silence);
+        std::mem::swap(&mut self.tail, &mut self.crossfade);
+    }
+
+    /// Produces the next `hop` frames of output. Returns false at the end of the input.
+    fn step(&mut self) -> bool {
+        let nominal = self.position.round() as usize;
+        let tolerance = if (self.speed - 1.0).abs() < f64::EPSILON {
+            0
+        } else {
+            self.tolerance
+        };
+        let earliest = nominal.saturating_sub(tolerance).max(self.input_start);
+        let latest = nominal + tolerance;
+        self.fill(latest + 2 * self.hop);
+        if self.source_exhausted && earliest >= self.input_end() {
+            return false;
+        }
+
+        let mut start = nominal.max(earliest);
+        if tolerance > 0 {
+            start = earliest + self.best_offset(earliest, latest, start - earliest);
+        }
+
+        self.output.clear();
+        self.output_pos = 0;
+        for i in 0..self.hop {
+            let w = self.fade_in[i];
+            for ch in 0..self.channels {
+                let old = self.tail[i * self.channels + ch];
+                self.output
+                    .push(old * (1.0 - w) + self.sample(start + i, ch) * w);
+            }
+        }
+        for i in 0..self.hop {
+            for ch in 0..self.channels {
+                self.tail[i * self.channels + ch] = self.sample(start + self.hop + i, ch);
+            }
+        }
+
+        self.position += self.hop as f64 * self.speed;
+        let keep_from = (self.position.round() as usize).saturating_sub(self.tolerance);
+        while self.input_start < keep_from.min(self.input_end()) {
+            self.input.drain(..self.channels);
+            self.input_start += 1;
+        }
+        true
+    }
+
+    /// The offset from `earliest` of the segment start up to `latest` whose input is the most
+    /// similar to the tail, preferring `preferred` on ties.
+    fn best_offset(&mut self, earliest: usize, latest: usize, preferred: usize) -> usize {
+        let channels = self.channels;
+        self.mono_tail.clear();
+        self.mono_tail.extend(
+            self.tail
+                .chunks(channels)
+                .map(|frame| frame.iter().sum::<f32>()),
+        );
+        let mut mono_input = std::mem::take(&mut self.mono_input);
+        mono_input.clear();
+        mono_input.extend(
+            (earliest..latest + self.hop)
+                .map(|i| (0..channels).map(|ch| self.sample(i, ch)).sum::<f32>()),
+        );
+
+        let tail = &self.mono_tail;
+        let similarity = |offset: usize, step: usize| -> f32 {
+            (0..tail.len())
+                .step_by(step)
+                .map(|i| tail[i] * mono_input[offset + i])
+                .sum()
+        };
+        let best = |candidates: &mut dyn Iterator<Item = usize>, step: usize| {
+            let mut best = (preferred, similarity(preferred, step));
+            for offset in candidates {
+                let similarity = similarity(offset, step);
+                if similarity > best.1 {
+                    best = (offset, similarity);
+                }
+            }
+            best.0
+        };
+        let max_offset = latest - earliest;
+        let coarse = best(&mut (0..=max_offset).step_by(COARSE_STEP), COARSE_STEP);
+        let around =
+            coarse.saturating_sub(COARSE_STEP - 1)..=(coarse + COARSE_STEP - 1).min(max_offset);
+        let offset = best(&mut around.into_iter(), 2);
+
+        self.mono_input = mono_input;
+        offset
+    }
+
+    fn input_end(&self) -> usize {
+        self.input_start + self.input.len() / self.channels
+    }
+
+    /// The input sample, or silence after the end of the input.
+    fn sample(&self, frame: usize, ch: usize) -> f32 {
+        let index = (frame - self.input_start) * self.channels + ch;
+        self.input.get(index).copied().unwrap_or(0.0)
+    }
+
+    /// Reads the silence and the source until the input has `end` frames.
+    fn fill(&mut self, end: usize) {
+        while self.silence > 0 && self.input_end() < end {
+            self.input.resize(self.input.len() + self.channels, 0.0);
+            self.silence -= 1;
+        }
+        while !self.source_exhausted && self.input_end() < end {
+            for _ in 0..self.channels {
+                match self.source.next() {
+                    Some(x) => self.input.push_back(x),
+                    None => {
+                        self.source_exhausted = true;
+                        // Pad the last frame
+                        self.input.push_back(0.0);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<I> Iterator for TimeStretch<I>
+where
+    I: Iterator<Item = f32>,
+{
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.output_pos == self.output.len() && !self.step() {
+            return None;
+        }
+        let ret = self.output[self.output_pos];
+        self.output_pos += 1;
+        Some(ret)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::f32::consts::PI;
+
+    use itertools::Itertools;
+
+    use super::TimeStretch;
+
+    const SAMPLE_RATE: u32 = 8000;
+
+    /// A stereo sine of `frequency` Hz for `seconds`, quieter on the right.
+    fn sine(frequency: f32, seconds: f32) -> Vec<f32> {
+        let frames = (seconds * SAMPLE_RATE as f32) as usize;
+        (0..frames)
+            .flat_map(|i| {
+                let x = (2.0 * PI * frequency * i as f32 / SAMPLE_RATE as f32).sin();
+                vec![x, 0.5 * x]
+            })
+            .collect()
+    }
+
+    fn stretch(input: &[f32], speed: f64) -> Vec<f32> {
+        let mut stretch = TimeStretch::new(input.iter().copied(), 2, SAMPLE_RATE);
+        stretch.set_speed(speed);
+        stretch.collect()
+    }
+
+    /// Zero crossings per second of the left channel, twice the frequency of a sine.
+    fn crossing_rate(samples: &[f32]) -> f32 {
+        let left = samples.iter().step_by(2).collect_vec();
+        let crossings = left
+            .iter()
+            .tuple_windows()
+            .filter(|(a, b)| (***a < 0.0) != (***b < 0.0))
+            .count();
+        crossings as f32 / left.len() as f32 * SAMPLE_RATE as f32
+    }
+
+    #[test]
+    fn test_normal_speed() {
+        let input = sine(440.0, 1.0);
+        let output = stretch(&input, 1.0);
+        let hop = 2 * SAMPLE_RATE as usize / 50;
+        assert!(output.len() >= input.len());
+        // Fades in, then reproduces the input
+        assert!(output[..hop]
+            .iter()
+            .zip(&input)
+            .all(|(o, i)| o.abs() <= i.abs()));
+        assert!(output[hop..input.len()]
+            .iter()
+            .zip(&input[hop..])
+            .all(|(o, i)| (o - i).abs() < 1e-5));
+    }
+
+    #[test]
+    fn test_leading_silence() {
+        let input = sine(440.0, 0.5);
+        let mut stretch = TimeStretch::new(input.iter().copied(), 2, SAMPLE_RATE);
+        stretch.reset(1000);
+        let output = stretch.collect_vec();
+        assert!(output[..2000].iter().all(|&x| x == 0.0));
+        assert!(output[2000..2000 + input.len()]
+            .iter()
+            .zip(&input)
+            .all(|(o, i)| (o - i).abs() < 1e-5));
+    }
+
+    /// Samples from the index in the second field, which can be set to seek
+    struct Seekable(Vec<f32>, usize);
+
+    impl Iterator for Seekable {
+        type Item = f32;
+        fn next(&mut self) -> Option<f32> {
+            self.1 += 1;
+            self.0.get(self.1 - 1).copied()
+        }
+    }
+
+    /// The largest difference between consecutive samples
+    fn max_step(samples: &[f32]) -> f32 {
+        samples
+            .iter()
+            .tuple_windows()
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn test_crossfade() {
+        // Mono, 1.0 and then 0.5
+        let input = [vec![1.0; 2000], vec![0.5; 2000]].concat();
+        let mut stretch = TimeStretch::new(Seekable(input, 0), 1, SAMPLE_RATE);
+        let before = stretch.by_ref().take(1000).collect_vec();
+        assert!(before[200..].iter().all(|&x| (x - 1.0).abs() < 1e-5));
+
+        stretch.get_mut().1 = 2000;
+        stretch.reset_with_crossfade(0);
+        let after = stretch.take(1000).collect_vec();
+        assert!((after[0] - 1.0).abs() < 0.01);
+        assert!(after.iter().tuple_windows().all(|(a, b)| a >= b));
+        assert!(after[200..].iter().all(|&x| (x - 0.5).abs() < 1e-5));
+    }
+
+    #[test]
+    fn test_loop_crossfade() {
+        // Mono. The loop ends near a peak and starts at a zero crossing.
+        let input = sine(440.0, 1.0).into_iter().step_by(2).collect_vec();
+        let (loop_start, loop_end) = (1000, 4005);
+        let hop = SAMPLE_RATE as usize / 50;
+        let play_loop = |crossfade: bool| {
+            let mut stretch = TimeStretch::new(Seekable(input.clone(), 0), 1, SAMPLE_RATE);
+            let mut output = stretch.by_ref().take(loop_end).collect_vec();
+            stretch.get_mut().1 = loop_start;
+            if crossfade {
+                stretch.reset_with_crossfade(0);
+            } else {
+                stretch.reset(0);
+            }
+            output.extend(stretch.take(2 * hop));
+            output
+        };
+
+        // The samples around the jump change no faster than the sine itself
+        let output = play_loop(true);
+        let sine_step = max_step(&input);
+        assert!(max_step(&output[loop_end - hop..]) < sine_step * 1.1);
+        assert!(output[loop_end + hop..]
+            .iter()
+            .zip(&input[loop_start + hop..])
+            .all(|(o, i)| (o - i).abs() < 1e-5));
+        // ... unlike fading in from silence
+        let output = play_loop(false);
+        assert!(max_step(&output[loop_end - hop..]) > 0.9);
+    }
+
+    #[test]
+    fn test_change_speed() {
+        let input = sine(440.0, 2.0);
+        for &speed in &[0.25, 0.5, 2.0] {
+            let output = stretch(&input, speed);
+            let expected_len = input.len() as f64 / speed;
+            let len_error = (output.len() as f64 - expected_len).abs() / expected_len;
+            assert!(len_error < 0.05, "{} {}", speed, output.len());
+            assert!((crossing_rate(&output) - 880.0).abs() < 20.0, "{}", speed);
+            // The channels stay apart
+            assert!(output
+                .chunks(2)
+                .all(|frame| (frame[0] - 2.0 * frame[1]).abs() < 1e-5));
+        }
+    }
+}
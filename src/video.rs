@@ -0,0 +1,419 @@
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::io::Write;
+use std::ops::Range;
+use std::path::Path;
+
+use itertools::Itertools;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::fonts::rasterize_text;
+use crate::fonts::FontLoadError;
+use crate::fonts::RasterizedText;
+use crate::formats::ass::AssColor;
+use crate::schema::Score;
+use crate::schema::Track;
+
+/// Style and layout of the rendered frames.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct VideoOptions {
+    pub resolution: (u32, u32),
+    pub frame_rate: f64,
+    /// Pixels per em.
+    pub font_size: f64,
+    /// Color of syllables that have been sung.
+    pub sung_color: AssColor,
+    /// Color of syllables that are yet to be sung.
+    pub unsung_color: AssColor,
+    pub background_color: AssColor,
+    /// Distance from the bottom of the frame to the bottom of the lower line.
+    pub margin_vertical: u32,
+    /// Seconds a track is shown before its first note.
+    pub lead_in: f64,
+}
+
+impl Default for VideoOptions {
+    fn default() -> Self {
+        Self {
+            resolution: (1280, 720),
+            frame_rate: 30.0,
+            font_size: 48.0,
+            sung_color: AssColor::rgb(172, 255, 84),
+            unsung_color: AssColor::rgb(255, 255, 255),
+            background_color: AssColor::rgb(0, 0, 0),
+            margin_vertical: 40,
+            lead_in: 3.0,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum VideoError {
+    #[error("{0}")]
+    IOError(#[from] io::Error),
+    #[error("{0}")]
+    PngError(#[from] png::EncodingError),
+    #[error("{0}")]
+    FontLoadError(#[from] FontLoadError),
+}
+
+/// Draws frames with the lyrics of the track being sung and the next one, on two lines that
+/// tracks take in turn. Sung syllables are wiped left to right over the times of their notes.
+pub struct FrameRenderer {
+    options: VideoOptions,
+    lines: Vec<Line>,
+}
+
+struct Line {
+    text: RasterizedText,
+    start_time: f64,
+    end_time: f64,
+    /// (time, x) pairs, between which the wipe moves linearly
+    wipe: Vec<(f64, f64)>,
+}
+
+impl FrameRenderer {
+    pub fn new(score: &Score, font_data: &[u8], options: VideoOptions) -> Result<Self, VideoError> {
+        let mut lines = Vec::new();
+        for track in score
+            .tracks
+            .iter()
+            .filter(|track| track.lyrics.is_some())
+            .sorted_by_key(|track| track.start_beat())
+        {
+            lines.extend(line(score, track, font_data, options.font_size)?);
+        }
+        Ok(Self { options, lines })
+    }
+
+    pub fn resolution(&self) -> (u32, u32) {
+        self.options.resolution
+    }
+
+    /// From the start of the music to the end of the last line.
+    pub fn default_time_range(&self) -> Range<f64> {
+        0.0..self.lines.last().map_or(0.0, |line| line.end_time + 1.0)
+    }
+
+    /// Times of the frames from `range.start`, at the frame rate.
+    pub fn frame_times(&self, range: Range<f64>) -> impl Iterator<Item = f64> {
+        let frame_rate = self.options.frame_rate;
+        let count = ((range.end - range.start) * frame_rate).ceil().max(0.0) as usize;
+        (0..count).map(move |i| range.start + i as f64 / frame_rate)
+    }
+
+    /// RGBA pixels of the frame at `time`, row by row, with straight alpha.
+    pub fn render(&self, time: f64) -> Vec<u8> {
+        let (width, height) = self.resolution();
+        let (width, height) = (width as usize, height as usize);
+        let background = premultiply(self.options.background_color);
+        let mut pixels = background
+            .iter()
+            .copied()
+            .cycle()
+            .take(4 * width * height)
+            .collect_vec();
+
+        let current = self.lines.iter().position(|line| time < line.end_time);
+        if let Some(current) = current {
+            if self.lines[current].start_time - self.options.lead_in <= time {
+                let line_height = (self.options.font_size * 1.5) as usize;
+                let descent = (self.options.font_size * 0.3) as usize;
+                let bottom = height.saturating_sub(self.options.margin_vertical as usize);
+                for index in current..(current + 2).min(self.lines.len()) {
+                    let line = &self.lines[index];
+                    let baseline = bottom.saturating_sub((1 - index % 2) * line_height + descent);
+                    let top = baseline.saturating_sub(line.text.end_cursor_pos.1);
+                    let left = width.saturating_sub(line.text.width) / 2;
+                    let wipe_x = if index == current {
+                        wipe_position(&line.wipe, time)
+                    } else {
+                        0.0
+                    };
+                    self.draw_line(&mut pixels, width, height, &line.text, (left, top), wipe_x);
+                }
+            }
+        }
+
+        for pixel in pixels.chunks_mut(4) {
+            let alpha = pixel[3] as u32;
+            for channel in &mut pixel[..3] {
+                // Fully transparent pixels stay black
+                let straight = (*channel as u32 * 255).checked_div(alpha).unwrap_or(0);
+                *channel = straight.min(255) as u8;
+            }
+        }
+        pixels
+    }
+
+    fn draw_line(
+        &self,
+        pixels: &mut [u8],
+        width: usize,
+        height: usize,
+        text: &RasterizedText,
+        (left, top): (usize, usize),
+        wipe_x: f64,
+    ) {
+        let sung = premultiply(self.options.sung_color);
+        let unsung = premultiply(self.options.unsung_color);
+        for y in 0..text.height.min(height.saturating_sub(top)) {
+            for x in 0..text.width.min(width.saturating_sub(left)) {
+                // The text is white, so every channel holds its coverage
+                let coverage = text.pixels[(y * text.width + x) * 4 + 3] as u32;
+                let color = if (x as f64) < wipe_x { sung } else { unsung };
+                let dst = &mut pixels[((top + y) * width + left + x) * 4..][..4];
+                let src_alpha = color[3] as u32 * coverage / 255;
+                for (d, &c) in dst.iter_mut().zip(color.iter()) {
+                    let src = c as u32 * coverage / 255;
+                    *d = (src + *d as u32 * (255 - src_alpha) / 255) as u8;
+                }
+            }
+        }
+    }
+}
+
+fn line(
+    score: &Score,
+    track: &Track,
+    font_data: &[u8],
+    font_size: f64,
+) -> Result<Option<Line>, FontLoadError> {
+    let syllables = track.syllables();
+    let lyrics = match &track.lyrics {
+        Some(lyrics) => lyrics,
+        None => return Ok(None),
+    };
+    let text = rasterize_text(font_data, &lyrics.text, font_size)?;
+
+    // Glyph clusters are byte offsets into the text
+    let x_at = |offset: usize| {
+        text.glyphs
+            .iter()
+            .find(|glyph| glyph.glyph_info.cluster as usize >= offset)
+            .map_or(text.end_cursor_pos.0, |glyph| glyph.cursor_pos.0) as f64
+    };
+    let mut wipe = Vec::new();
+    let mut offset = 0;
+    for syllable in &syllables {
+        let start = offset + (syllable.text.len() - syllable.text.trim_start().len());
+        let end = offset + syllable.text.trim_end().len();
+        offset += syllable.text.len();
+        let total = syllable
+            .notes
+            .iter()
+            .map(|(start, end)| score.beat_to_time(end) - score.beat_to_time(start))
+            .sum::<f64>();
+        let (start_x, end_x) = (x_at(start), x_at(end.max(start)));
+        let mut x = start_x;
+        for (note_start, note_end) in &syllable.notes {
+            let note_start = score.beat_to_time(note_start);
+            let note_end = score.beat_to_time(note_end);
+            let next_x = if total > 0.0 {
+                x + (end_x - start_x) * (note_end - note_start) / total
+            } else {
+                end_x
+            };
+            wipe.push((note_start, x));
+            wipe.push((note_end, next_x));
+            x = next_x;
+        }
+    }
+    let (start_time, end_time) = match (wipe.first(), wipe.last()) {
+        (Some(first), Some(last)) => (first.0, last.0),
+        _ => return Ok(None),
+    };
+    Ok(Some(Line {
+        text,
+        start_time,
+        end_time,
+        wipe,
+    }))
+}
+
+/// The x the wipe has reached at `time`, given (time, x) keyframes in order.
+fn wipe_position(wipe: &[(f64, f64)], time: f64) -> f64 {
+    match wipe.iter().position(|&(t, _)| time < t) {
+        None => wipe.last().map_or(0.0, |&(_, x)| x),
+        Some(0) => 0.0,
+        Some(i) => {
+            let ((t0, x0), (t1, x1)) = (wipe[i - 1], wipe[i]);
+            x0 + (x1 - x0) * (time - t0) / (t1 - t0)
+        }
+    }
+}
+
+fn premultiply(color: AssColor) -> [u8; 4] {
+    let alpha = color.alpha as u32;
+    let channel = |c: u8| (c as u32 * alpha / 255) as u8;
+    [
+        channel(color.red),
+        channel(color.green),
+        channel(color.blue),
+        color.alpha,
+    ]
+}
+
+/// Writes a PNG per frame into `directory`, named by the index of the frame from 0 like
+/// `000042.png`. Returns the number of frames.
+pub fn write_png_sequence(
+    renderer: &FrameRenderer,
+    range: Range<f64>,
+    directory: &Path,
+) -> Result<usize, VideoError> {
+    let (width, height) = renderer.resolution();
+    let mut count = 0;
+    for (i, time) in renderer.frame_times(range).enumerate() {
+        let file = File::create(directory.join(format!("{:06}.png", i)))?;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()?
+            .write_image_data(&renderer.render(time))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Writes the frames one after another as raw RGBA, e.g. for
+/// `ffmpeg -f rawvideo -pixel_format rgba -video_size 1280x720 -framerate 30 -i -`.
+/// Returns the number of frames.
+pub fn write_raw_frames(
+    renderer: &FrameRenderer,
+    range: Range<f64>,
+    mut out: impl Write,
+) -> Result<usize, VideoError> {
+    let mut count = 0;
+    for time in renderer.frame_times(range) {
+        out.write_all(&renderer.render(time))?;
+        count += 1;
+    }
+    out.flush()?;
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::ops::Range;
+
+    use num::BigRational;
+
+    use super::wipe_position;
+    use super::write_png_sequence;
+    use super::write_raw_frames;
+    use super::FrameRenderer;
+    use super::VideoOptions;
+    use crate::formats::test_util::sample_score;
+    use crate::schema::BeatPosition;
+
+    const FONT: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/FiraSans-Regular.ttf"
+    ));
+
+    /// Renders `sample_score` and the same track again from beat 8, so that both lines are shown.
+    fn sample_renderer() -> FrameRenderer {
+        let mut score = sample_score();
+        let mut track = score.tracks[0].clone();
+        track.start_beat = BeatPosition(BigRational::from_integer(8.into()));
+        score.tracks.push_back(track);
+        let options = VideoOptions {
+            resolution: (320, 120),
+            font_size: 24.0,
+            margin_vertical: 10,
+            ..Default::default()
+        };
+        FrameRenderer::new(&score, FONT, options).unwrap()
+    }
+
+    /// Counts the sung and unsung text pixels in `rows`, checking that they are left and right of
+    /// `wipe_x` respectively.
+    fn count_wiped(pixels: &[u8], width: usize, rows: Range<usize>, wipe_x: f64) -> (usize, usize) {
+        let (mut sung, mut unsung) = (0, 0);
+        for y in rows {
+            for x in 0..width {
+                let pixel = &pixels[(y * width + x) * 4..][..4];
+                if pixel[..3] == [0, 0, 0] {
+                    continue;
+                }
+                // Sung text is green, and unsung text is white or grey
+                let is_sung = pixel[0] != pixel[1];
+                assert_eq!(is_sung, (x as f64) < wipe_x, "pixel at ({}, {})", x, y);
+                if is_sung {
+                    sung += 1;
+                } else {
+                    unsung += 1;
+                }
+            }
+        }
+        (sung, unsung)
+    }
+
+    #[test]
+    fn test_render() {
+        let renderer = sample_renderer();
+        let (width, height) = (320, 120);
+        // The upper slot ends a line height above the bottom margin
+        let split = height - 10 - 36;
+
+        // "Hello" is half sung. The first line takes the upper slot, with the next one below.
+        let pixels = renderer.render(0.25);
+        assert_eq!(pixels.len(), 4 * width * height);
+        let line = &renderer.lines[0];
+        let left = (width - line.text.width) / 2;
+        let wipe_x = left as f64 + wipe_position(&line.wipe, 0.25);
+        assert!(left as f64 + 10.0 < wipe_x);
+        let (sung, unsung) = count_wiped(&pixels, width, 0..split, wipe_x);
+        assert!(sung > 0 && unsung > 0);
+        let (sung, unsung) = count_wiped(&pixels, width, split..height, 0.0);
+        assert!(sung == 0 && unsung > 0);
+
+        // After the first line, the second one takes the lower slot
+        let pixels = renderer.render(2.5);
+        assert_eq!(count_wiped(&pixels, width, 0..split, 0.0), (0, 0));
+        let (sung, unsung) = count_wiped(&pixels, width, split..height, 0.0);
+        assert!(sung == 0 && unsung > 0);
+    }
+
+    #[test]
+    fn test_write_frames() {
+        let renderer = sample_renderer();
+        let frame_size = 4 * 320 * 120;
+
+        let mut out = Vec::new();
+        assert_eq!(write_raw_frames(&renderer, 0.0..0.1, &mut out).unwrap(), 3);
+        assert_eq!(out.len(), 3 * frame_size);
+
+        let directory = std::env::temp_dir().join(format!("karaoke-frames-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let count = write_png_sequence(&renderer, 0.0..0.1, &directory);
+        let decoded = File::open(directory.join("000001.png")).map(|file| {
+            let mut reader = png::Decoder::new(file).read_info().unwrap();
+            let mut pixels = vec![0; reader.output_buffer_size()];
+            reader.next_frame(&mut pixels).unwrap();
+            let info = reader.info();
+            ((info.width, info.height), pixels)
+        });
+        std::fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(count.unwrap(), 3);
+        let (size, pixels) = decoded.unwrap();
+        assert_eq!(size, (320, 120));
+        assert_eq!(pixels, renderer.render(1.0 / 30.0));
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn test_wipe_position() {
+        let wipe = [(1.0, 0.0), (2.0, 10.0), (3.0, 10.0), (4.0, 30.0)];
+        assert_eq!(wipe_position(&wipe, 0.0), 0.0);
+        assert_eq!(wipe_position(&wipe, 1.5), 5.0);
+        assert_eq!(wipe_position(&wipe, 2.5), 10.0);
+        assert_eq!(wipe_position(&wipe, 3.5), 20.0);
+        assert_eq!(wipe_position(&wipe, 5.0), 30.0);
+    }
+}